@@ -3,22 +3,29 @@ pub mod cache;
 use self::cache::UsersCache;
 use crate::{
     config::Config,
-    db::{delete_user_logs, writer::FlushBuffer},
+    db::{
+        delete_user_logs, get_user_name_history, schema::OptOutEventType, write_opt_out_event,
+        writer::FlushBuffer,
+    },
     error::Error,
     Result,
 };
 use anyhow::Context;
 use dashmap::DashSet;
-use std::{collections::HashMap, sync::Arc};
-use tracing::{debug, info};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::time::sleep;
+use tracing::{debug, error, info, warn};
 use twitch_api::{helix::users::GetUsersRequest, twitch_oauth2::AppAccessToken, HelixClient};
 
+const DELETE_ATTEMPTS: u32 = 3;
+
 #[derive(Clone)]
 pub struct App {
     pub helix_client: HelixClient<'static, reqwest::Client>,
     pub token: Arc<AppAccessToken>,
     pub users: UsersCache,
     pub optout_codes: Arc<DashSet<String>>,
+    pub optin_codes: Arc<DashSet<String>>,
     pub db: Arc<clickhouse::Client>,
     pub config: Arc<Config>,
     pub flush_buffer: FlushBuffer,
@@ -103,6 +110,23 @@ impl App {
         Ok(users)
     }
 
+    /// Current and previous logins of a user
+    pub async fn get_user_logins(&self, user_id: &str) -> Result<Vec<String>> {
+        let mut user_logins: Vec<String> = get_user_name_history(&self.db, user_id)
+            .await?
+            .into_iter()
+            .map(|name| name.user_login)
+            .collect();
+        user_logins.extend(
+            self.get_users(vec![user_id.to_owned()], vec![], false)
+                .await?
+                .into_values(),
+        );
+        user_logins.sort_unstable();
+        user_logins.dedup();
+        Ok(user_logins)
+    }
+
     pub async fn get_user_id_by_name(&self, name: &str) -> Result<String> {
         match self.users.get_id(name) {
             Some(Some(id)) => Ok(id),
@@ -126,17 +150,62 @@ impl App {
     }
 
     pub async fn optout_user(&self, user_id: &str) -> anyhow::Result<()> {
-        delete_user_logs(&self.db, user_id)
-            .await
-            .context("Could not delete logs")?;
-
+        // Stop logging new messages first, so nothing gets written after the deletion
         self.config.opt_out.insert(user_id.to_owned(), true);
         self.config.save()?;
+        write_opt_out_event(&self.db, user_id, OptOutEventType::OptOut)
+            .await
+            .context("Could not record opt out")?;
+
+        self.flush_buffer.remove_user_messages(user_id).await;
+        self.delete_user_logs(user_id).await?;
+
         info!("User {user_id} opted out");
 
         Ok(())
     }
 
+    /// Deletes the logs of a user, retrying a few times so a transient failure
+    /// doesn't leave an opted out user with their logs still stored
+    pub async fn delete_user_logs(&self, user_id: &str) -> anyhow::Result<()> {
+        // Looked up before the deletion, as it also removes the name history
+        let user_logins = self.get_user_logins(user_id).await.unwrap_or_else(|err| {
+            warn!("Could not get logins of user {user_id}, their message deletions will be kept: {err}");
+            Vec::new()
+        });
+
+        let mut attempt = 1;
+        loop {
+            match delete_user_logs(&self.db, user_id, &user_logins).await {
+                Ok(()) => return Ok(()),
+                Err(err) if attempt < DELETE_ATTEMPTS => {
+                    warn!("Could not delete logs of user {user_id} (attempt {attempt}): {err}");
+                    sleep(Duration::from_secs(2u64.pow(attempt))).await;
+                    attempt += 1;
+                }
+                Err(err) => {
+                    error!("Could not delete logs of opted out user {user_id}: {err}");
+                    return Err(err).context("Could not delete logs");
+                }
+            }
+        }
+    }
+
+    /// Returns `false` if the user was not opted out
+    pub async fn optin_user(&self, user_id: &str) -> anyhow::Result<bool> {
+        if self.config.opt_out.remove(user_id).is_none() {
+            return Ok(false);
+        }
+        self.config.save()?;
+        write_opt_out_event(&self.db, user_id, OptOutEventType::OptIn)
+            .await
+            .context("Could not record opt in")?;
+
+        info!("User {user_id} opted in");
+
+        Ok(true)
+    }
+
     pub fn check_opted_out(&self, channel_id: &str, user_id: Option<&str>) -> Result<()> {
         if self.config.opt_out.contains_key(channel_id) {
             return Err(Error::ChannelOptedOut);
@@ -150,4 +219,13 @@ impl App {
 
         Ok(())
     }
+
+    /// For requests which are not about a single channel
+    pub fn check_user_opted_out(&self, user_id: &str) -> Result<()> {
+        if self.config.opt_out.contains_key(user_id) {
+            return Err(Error::UserOptedOut);
+        }
+
+        Ok(())
+    }
 }
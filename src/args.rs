@@ -24,4 +24,13 @@ pub enum Command {
         #[clap(short, long, default_value_t = 1)]
         jobs: usize,
     },
+    /// Log raw IRC messages from a file instead of connecting to Twitch
+    Replay {
+        /// File with one raw IRC message per line (None specified = read from stdin)
+        #[clap(short, long, value_parser)]
+        file: Option<std::path::PathBuf>,
+        /// Replay speed multiplier relative to the original message timestamps (0 = no delay)
+        #[clap(short, long, default_value_t = 1.0)]
+        speed: f64,
+    },
 }
@@ -0,0 +1,180 @@
+use crate::{
+    db::{
+        read_last_connection_event, read_last_message_timestamp,
+        schema::{ConnectionEvent, ConnectionEventType},
+        write_connection_events,
+    },
+    Result,
+};
+use chrono::Utc;
+use clickhouse::Client;
+use dashmap::DashMap;
+use std::sync::Arc;
+use tracing::{debug, error, info};
+
+struct ChannelConnection {
+    connected: bool,
+    last_message_at: Option<u64>,
+}
+
+/// Records when channels are joined and left, so missing logs can be marked as gaps
+#[derive(Clone)]
+pub struct ConnectionTracker {
+    db: Arc<Client>,
+    channels: Arc<DashMap<String, ChannelConnection>>,
+    /// Channel logins which were moved to another shard, with the time they were moved at
+    moved_channels: Arc<DashMap<String, u64>>,
+}
+
+impl ConnectionTracker {
+    pub fn new(db: Arc<Client>) -> Self {
+        Self {
+            db,
+            channels: Arc::default(),
+            moved_channels: Arc::default(),
+        }
+    }
+
+    pub fn message_received(&self, channel_id: &str, timestamp: u64) {
+        if let Some(mut channel) = self.channels.get_mut(channel_id) {
+            channel.last_message_at = Some(timestamp);
+        }
+    }
+
+    /// The channel's connection was closed to move it to another shard, it will be joined again
+    pub fn moved(&self, channel_login: String) {
+        self.moved_channels
+            .insert(channel_login, Utc::now().timestamp_millis() as u64);
+    }
+
+    /// Returns the event type that was recorded
+    pub async fn joined(
+        &self,
+        channel_id: &str,
+        channel_login: &str,
+    ) -> Result<ConnectionEventType> {
+        let now = Utc::now().timestamp_millis() as u64;
+        let mut events = Vec::with_capacity(2);
+
+        let previous_state = self
+            .channels
+            .get(channel_id)
+            .map(|channel| (channel.connected, channel.last_message_at));
+        let moved_at = self
+            .moved_channels
+            .remove(channel_login)
+            .map(|(_, moved_at)| moved_at);
+
+        let event_type = match (previous_state, moved_at) {
+            // The time of the disconnect is known exactly when moving between shards
+            (Some((true, _)), Some(moved_at)) => {
+                events.push(ConnectionEvent {
+                    channel_id: channel_id.to_owned(),
+                    timestamp: moved_at,
+                    event_type: ConnectionEventType::Disconnect,
+                });
+                ConnectionEventType::Reconnect
+            }
+            // The client reconnected without us noticing the disconnect.
+            // The connection could have been lost any time after the last received message
+            (Some((true, last_message_at)), None) => {
+                if let Some(last_message_at) = last_message_at {
+                    events.push(ConnectionEvent {
+                        channel_id: channel_id.to_owned(),
+                        timestamp: last_message_at,
+                        event_type: ConnectionEventType::UncertainDisconnect,
+                    });
+                }
+                ConnectionEventType::Reconnect
+            }
+            (Some((false, _)), _) => ConnectionEventType::Reconnect,
+            (None, _) => {
+                if let Some(disconnected_at) = self.find_unrecorded_disconnect(channel_id).await? {
+                    info!("Channel {channel_id} was not disconnected properly, recording a gap since {disconnected_at}");
+                    events.push(ConnectionEvent {
+                        channel_id: channel_id.to_owned(),
+                        timestamp: disconnected_at,
+                        event_type: ConnectionEventType::UncertainDisconnect,
+                    });
+                }
+                ConnectionEventType::Connect
+            }
+        };
+
+        self.channels.insert(
+            channel_id.to_owned(),
+            ChannelConnection {
+                connected: true,
+                last_message_at: None,
+            },
+        );
+
+        events.push(ConnectionEvent {
+            channel_id: channel_id.to_owned(),
+            timestamp: now,
+            event_type,
+        });
+        write_connection_events(&self.db, &events).await?;
+
+        debug!("Recorded {event_type:?} event for channel {channel_id}");
+        Ok(event_type)
+    }
+
+    pub async fn parted(&self, channel_id: &str) -> Result<()> {
+        if let Some(mut channel) = self.channels.get_mut(channel_id) {
+            channel.connected = false;
+        }
+
+        let event = ConnectionEvent {
+            channel_id: channel_id.to_owned(),
+            timestamp: Utc::now().timestamp_millis() as u64,
+            event_type: ConnectionEventType::Disconnect,
+        };
+        write_connection_events(&self.db, &[event]).await
+    }
+
+    /// Records a disconnect for all joined channels, used on shutdown
+    pub async fn disconnect_all(&self) {
+        let now = Utc::now().timestamp_millis() as u64;
+        let events: Vec<_> = self
+            .channels
+            .iter_mut()
+            .filter(|channel| channel.connected)
+            .map(|mut channel| {
+                channel.connected = false;
+                ConnectionEvent {
+                    channel_id: channel.key().clone(),
+                    timestamp: now,
+                    event_type: ConnectionEventType::Disconnect,
+                }
+            })
+            .collect();
+
+        if events.is_empty() {
+            return;
+        }
+
+        debug!("Recording disconnect for {} channels", events.len());
+        if let Err(err) = write_connection_events(&self.db, &events).await {
+            error!("Could not record disconnect events: {err}");
+        }
+    }
+
+    /// If the previous run did not exit cleanly, there is no disconnect event for the channel.
+    /// In that case, the gap starts at the last logged message.
+    async fn find_unrecorded_disconnect(&self, channel_id: &str) -> Result<Option<u64>> {
+        match read_last_connection_event(&self.db, channel_id).await? {
+            Some(event)
+                if !matches!(
+                    event.event_type,
+                    ConnectionEventType::Disconnect | ConnectionEventType::UncertainDisconnect
+                ) =>
+            {
+                let last_message_at =
+                    read_last_message_timestamp(&self.db, channel_id, event.timestamp).await?;
+                Ok(Some(last_message_at.unwrap_or(event.timestamp)))
+            }
+            _ => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,650 @@
+mod connections;
+mod recent_messages;
+mod role;
+mod shard;
+
+use self::{
+    connections::ConnectionTracker, recent_messages::RecentMessagesClient, role::Role,
+    shard::ShardManager,
+};
+use crate::{
+    app::App,
+    db::{
+        get_user_stats, read_message_keys,
+        schema::{StructuredMessage, UnstructuredMessage},
+    },
+    logs::{
+        extract::{extract_channel_and_user_from_raw, extract_raw_timestamp},
+        schema::LogRangeParams,
+    },
+    source::{MessageSink, MessageSource},
+    ShutdownRx,
+};
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use chrono::Utc;
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::mpsc::Receiver,
+    time::{sleep, Instant},
+};
+use tracing::{debug, error, info, log::warn, trace};
+use twitch_irc::{
+    login::LoginCredentials,
+    message::{AsRawIRC, IRCMessage, PrivmsgMessage, ServerMessage},
+};
+
+const CHANNEL_REJOIN_INTERVAL_SECONDS: u64 = 3600;
+const CHANENLS_REFETCH_RETRY_INTERVAL_SECONDS: u64 = 5;
+/// Minimum time between command replies in a single channel
+const REPLY_COOLDOWN_SECONDS: u64 = 3;
+
+type Shards<C> = Arc<Mutex<ShardManager<C>>>;
+
+#[derive(Debug)]
+pub enum BotMessage {
+    JoinChannels(Vec<String>),
+    PartChannels(Vec<String>),
+}
+
+lazy_static! {
+    static ref MESSAGES_RECEIVED_COUNTERS: IntCounterVec = register_int_counter_vec!(
+        "rustlog_messages_received",
+        "How many messages were written",
+        &["channel_id"]
+    )
+    .unwrap();
+    static ref MESSAGES_BACKFILLED_COUNTERS: IntCounterVec = register_int_counter_vec!(
+        "rustlog_messages_backfilled",
+        "How many missed messages were written from the recent-messages service",
+        &["channel_id"]
+    )
+    .unwrap();
+}
+
+const COMMAND_PREFIX: &str = "!rustlog ";
+
+/// Logs messages from Twitch chat
+pub struct TwitchSource<C: LoginCredentials + Clone> {
+    login_credentials: C,
+    command_rx: Receiver<BotMessage>,
+}
+
+impl<C: LoginCredentials + Clone> TwitchSource<C> {
+    pub fn new(login_credentials: C, command_rx: Receiver<BotMessage>) -> Self {
+        Self {
+            login_credentials,
+            command_rx,
+        }
+    }
+}
+
+#[async_trait]
+impl<C: LoginCredentials + Clone> MessageSource for TwitchSource<C> {
+    async fn run(self: Box<Self>, sink: MessageSink, shutdown_rx: ShutdownRx) {
+        let bot = Bot::new(sink);
+        bot.run(self.login_credentials, shutdown_rx, self.command_rx)
+            .await;
+    }
+}
+
+#[derive(Clone)]
+struct Bot {
+    app: App,
+    sink: MessageSink,
+    connections: ConnectionTracker,
+    recent_messages: Option<RecentMessagesClient>,
+    /// When the last command reply was sent in each channel
+    last_replies: Arc<DashMap<String, Instant>>,
+}
+
+impl Bot {
+    pub fn new(sink: MessageSink) -> Bot {
+        let app = sink.app().clone();
+        let connections = ConnectionTracker::new(app.db.clone());
+        let recent_messages = app
+            .config
+            .recent_messages_url
+            .clone()
+            .map(RecentMessagesClient::new);
+        Self {
+            app,
+            sink,
+            connections,
+            recent_messages,
+            last_replies: Arc::default(),
+        }
+    }
+
+    pub async fn run<C: LoginCredentials + Clone>(
+        self,
+        login_credentials: C,
+        mut shutdown_rx: ShutdownRx,
+        mut command_rx: Receiver<BotMessage>,
+    ) {
+        let (shard_manager, mut receiver) =
+            ShardManager::new(login_credentials, self.app.config.channels_per_connection);
+        let shards: Shards<C> = Arc::new(Mutex::new(shard_manager));
+
+        let app = self.app.clone();
+        let join_shards = shards.clone();
+        tokio::spawn(async move {
+            loop {
+                let channel_ids = app.config.channels.read().unwrap().clone();
+
+                let interval = match app
+                    .get_users(Vec::from_iter(channel_ids), vec![], true)
+                    .await
+                {
+                    Ok(users) => {
+                        info!("Joining {} channels", users.len());
+                        let mut shards = join_shards.lock().unwrap();
+                        for channel_login in users.into_values() {
+                            debug!("Logging channel {channel_login}");
+                            shards.join(channel_login).expect("Failed to join channel");
+                        }
+                        info!("Using {} shards", shards.shard_count());
+                        CHANNEL_REJOIN_INTERVAL_SECONDS
+                    }
+                    Err(err) => {
+                        error!("Could not fetch users list: {err}");
+                        CHANENLS_REFETCH_RETRY_INTERVAL_SECONDS
+                    }
+                };
+                sleep(Duration::from_secs(interval)).await;
+            }
+        });
+
+        let bot = self.clone();
+        let msg_shards = shards.clone();
+        tokio::spawn(async move {
+            while let Some(msg) = command_rx.recv().await {
+                match msg {
+                    BotMessage::JoinChannels(channels) => {
+                        if let Err(err) = bot
+                            .update_channels(
+                                &msg_shards,
+                                &channels.iter().map(String::as_str).collect::<Vec<_>>(),
+                                ChannelAction::Join,
+                            )
+                            .await
+                        {
+                            error!("Could not join channels: {err}");
+                        }
+                    }
+                    BotMessage::PartChannels(channels) => {
+                        if let Err(err) = bot
+                            .update_channels(
+                                &msg_shards,
+                                &channels.iter().map(String::as_str).collect::<Vec<_>>(),
+                                ChannelAction::Part,
+                            )
+                            .await
+                        {
+                            error!("Could not join channels: {err}");
+                        }
+                    }
+                }
+            }
+        });
+
+        loop {
+            tokio::select! {
+                Some(msg) = receiver.recv() => {
+                    if let Err(e) = self.handle_message(msg, &shards).await {
+                        error!("Could not handle message: {e}");
+                    }
+                }
+                _ = shutdown_rx.changed() => {
+                    debug!("Shutting down bot task");
+                    self.connections.disconnect_all().await;
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn handle_message<C: LoginCredentials + Clone>(
+        &self,
+        msg: ServerMessage,
+        shards: &Shards<C>,
+    ) -> anyhow::Result<()> {
+        match &msg {
+            ServerMessage::Join(join) => {
+                self.track_connection(join.channel_login.clone(), ChannelAction::Join);
+            }
+            ServerMessage::Part(part) => {
+                self.track_connection(part.channel_login.clone(), ChannelAction::Part);
+            }
+            _ => (),
+        }
+
+        if let ServerMessage::Privmsg(privmsg) = &msg {
+            trace!("Processing message {}", privmsg.message_text);
+            if let Some(cmd) = privmsg.message_text.strip_prefix(COMMAND_PREFIX) {
+                match self.handle_command(cmd, shards, privmsg).await {
+                    Ok(Some(reply)) => self.reply(shards, privmsg, reply).await,
+                    Ok(None) => (),
+                    Err(err) => warn!("Could not handle command {cmd}: {err:#}"),
+                }
+            }
+        }
+
+        self.write_message(msg).await?;
+
+        Ok(())
+    }
+
+    fn track_connection(&self, channel_login: String, action: ChannelAction) {
+        let bot = self.clone();
+        tokio::spawn(async move {
+            let result = async {
+                let channel_id = bot.app.get_user_id_by_name(&channel_login).await?;
+                match action {
+                    ChannelAction::Join => {
+                        bot.connections.joined(&channel_id, &channel_login).await?;
+                        if let Some(recent_messages) = &bot.recent_messages {
+                            if let Err(err) = bot
+                                .backfill(recent_messages, &channel_login, &channel_id)
+                                .await
+                            {
+                                warn!("Could not backfill messages in channel {channel_login}: {err:#}");
+                            }
+                        }
+                        Ok(())
+                    }
+                    ChannelAction::Part => bot.connections.parted(&channel_id).await,
+                }
+            }
+            .await;
+
+            if let Err(err) = result {
+                error!("Could not record connection event for channel {channel_login}: {err}");
+            }
+        });
+    }
+
+    /// Writes messages from the recent-messages service which have not been logged yet
+    async fn backfill(
+        &self,
+        recent_messages: &RecentMessagesClient,
+        channel_login: &str,
+        channel_id: &str,
+    ) -> anyhow::Result<()> {
+        let messages: Vec<_> = recent_messages
+            .fetch(channel_login)
+            .await?
+            .into_iter()
+            .filter(|msg| msg.irc_message.command != "ROOMSTATE")
+            .filter_map(|msg| {
+                let timestamp = extract_raw_timestamp(&msg.irc_message).or(msg.received_at)?;
+                self.structure_message(&msg.irc_message, timestamp)
+            })
+            .filter(|msg| msg.channel_id == channel_id)
+            .collect();
+
+        let (Some(from), Some(to)) = (
+            messages.iter().map(|msg| msg.timestamp).min(),
+            messages.iter().map(|msg| msg.timestamp).max(),
+        ) else {
+            return Ok(());
+        };
+
+        // Messages with an id are matched by it, other messages by all of their key fields
+        let mut existing_ids = HashSet::new();
+        let mut existing_keys = HashSet::new();
+        let buffered_keys = self
+            .app
+            .flush_buffer
+            .messages_by_channel(from..to + 1, channel_id)
+            .await
+            .into_iter()
+            .map(|msg| msg.key());
+        for key in read_message_keys(&self.app.db, channel_id, from, to)
+            .await?
+            .into_iter()
+            .chain(buffered_keys)
+        {
+            if key.id.is_nil() {
+                existing_keys.insert(key);
+            } else {
+                existing_ids.insert(key.id);
+            }
+        }
+
+        let mut count = 0;
+        for msg in messages {
+            let key = msg.key();
+            let is_duplicate = if key.id.is_nil() {
+                !existing_keys.insert(key)
+            } else {
+                !existing_ids.insert(key.id)
+            };
+
+            if !is_duplicate {
+                self.sink.write_structured(msg).await?;
+                count += 1;
+            }
+        }
+
+        if count > 0 {
+            info!("Backfilled {count} messages in channel {channel_login}");
+            MESSAGES_BACKFILLED_COUNTERS
+                .with_label_values(&[channel_id])
+                .inc_by(count);
+        }
+
+        Ok(())
+    }
+
+    /// Sends a reply to a command, if the bot is logged in and the channel is not on cooldown
+    async fn reply<C: LoginCredentials + Clone>(
+        &self,
+        shards: &Shards<C>,
+        privmsg: &PrivmsgMessage,
+        text: String,
+    ) {
+        if self.app.config.bot_token.read().unwrap().is_none() {
+            debug!("Not replying to command, no authenticated login is configured");
+            return;
+        }
+
+        let now = Instant::now();
+        let cooldown = Duration::from_secs(REPLY_COOLDOWN_SECONDS);
+        match self.last_replies.entry(privmsg.channel_id.clone()) {
+            dashmap::Entry::Occupied(mut entry) => {
+                if now.duration_since(*entry.get()) < cooldown {
+                    debug!(
+                        "Not replying to command in {}, on cooldown",
+                        privmsg.channel_login
+                    );
+                    return;
+                }
+                entry.insert(now);
+            }
+            dashmap::Entry::Vacant(entry) => {
+                entry.insert(now);
+            }
+        }
+
+        let client = shards.lock().unwrap().client_for(&privmsg.channel_login);
+        if let Some(client) = client {
+            if let Err(err) = client.say_in_reply_to(privmsg, text).await {
+                warn!("Could not send reply in {}: {err}", privmsg.channel_login);
+            }
+        }
+    }
+
+    async fn write_message(&self, msg: ServerMessage) -> anyhow::Result<()> {
+        // Ignore
+        if matches!(msg, ServerMessage::RoomState(_)) {
+            return Ok(());
+        }
+
+        let irc_message = IRCMessage::from(msg);
+
+        if let Some((channel_id, _)) = extract_channel_and_user_from_raw(&irc_message) {
+            if !channel_id.is_empty() {
+                MESSAGES_RECEIVED_COUNTERS
+                    .with_label_values(&[channel_id])
+                    .inc();
+            }
+
+            let timestamp = extract_raw_timestamp(&irc_message)
+                .unwrap_or_else(|| Utc::now().timestamp_millis().try_into().unwrap());
+            self.connections.message_received(channel_id, timestamp);
+
+            if let Some(msg) = self.structure_message(&irc_message, timestamp) {
+                self.sink.write_structured(msg).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Converts a raw message into the form it is logged as.
+    /// Returns `None` if the message should not be logged.
+    fn structure_message(
+        &self,
+        irc_message: &IRCMessage,
+        timestamp: u64,
+    ) -> Option<StructuredMessage<'static>> {
+        let (channel_id, maybe_user_id) = extract_channel_and_user_from_raw(irc_message)?;
+
+        let raw_irc = irc_message.as_raw_irc();
+        let unstructured = UnstructuredMessage {
+            channel_id,
+            user_id: maybe_user_id.unwrap_or_default(),
+            timestamp,
+            raw: &raw_irc,
+        };
+        self.sink.structure(&unstructured)
+    }
+
+    /// Returns the reply to the command, if any
+    async fn handle_command<C: LoginCredentials + Clone>(
+        &self,
+        cmd: &str,
+        shards: &Shards<C>,
+        privmsg: &PrivmsgMessage,
+    ) -> anyhow::Result<Option<String>> {
+        debug!("Processing command {cmd}");
+        let mut split = cmd.split_whitespace();
+        let Some(action) = split.next() else {
+            return Ok(None);
+        };
+        let args: Vec<&str> = split.collect();
+        let role = Role::of_sender(privmsg, &self.app.config);
+
+        let reply = match action {
+            "join" => {
+                require_role(role, Role::Admin)?;
+                self.update_channels(shards, &args, ChannelAction::Join)
+                    .await?;
+                format!("Joined {}", args.join(", "))
+            }
+            "leave" | "part" => {
+                // Broadcasters can make the bot leave their own channel
+                let channels = if args.is_empty() {
+                    require_role(role, Role::Broadcaster)?;
+                    vec![privmsg.channel_login.as_str()]
+                } else {
+                    require_role(role, Role::Admin)?;
+                    args
+                };
+                self.update_channels(shards, &channels, ChannelAction::Part)
+                    .await?;
+                format!("Left {}", channels.join(", "))
+            }
+            "pause" | "resume" => {
+                let (channel_id, channel_login) = match args.first() {
+                    None => {
+                        require_role(role, Role::Broadcaster)?;
+                        (privmsg.channel_id.clone(), privmsg.channel_login.clone())
+                    }
+                    Some(channel_login) => {
+                        require_role(role, Role::Admin)?;
+                        let channel_id = self.app.get_user_id_by_name(channel_login).await?;
+                        (channel_id, channel_login.to_string())
+                    }
+                };
+
+                if action == "pause" {
+                    info!("Pausing logging in channel {channel_login}");
+                    self.app.config.paused_channels.insert(channel_id);
+                } else {
+                    info!("Resuming logging in channel {channel_login}");
+                    self.app.config.paused_channels.remove(&channel_id);
+                }
+                self.app.config.save()?;
+
+                format!("Logging {action}d in {channel_login}")
+            }
+            "status" => {
+                require_role(role, Role::Moderator)?;
+                let state = if self
+                    .app
+                    .config
+                    .paused_channels
+                    .contains(&privmsg.channel_id)
+                {
+                    "paused"
+                } else {
+                    "active"
+                };
+                let channel_count = self.app.config.channels.read().unwrap().len();
+                let shard_count = shards.lock().unwrap().shard_count();
+                format!("Logging is {state} in this channel. Logging {channel_count} channels on {shard_count} connections")
+            }
+            "channels" => {
+                require_role(role, Role::Admin)?;
+                let channel_count = self.app.config.channels.read().unwrap().len();
+                let paused_count = self.app.config.paused_channels.len();
+                format!("Logging {channel_count} channels, {paused_count} of them are paused")
+            }
+            "stats" => {
+                require_role(role, Role::Moderator)?;
+                let user_login = args
+                    .first()
+                    .context("No user specified")?
+                    .trim_start_matches('@')
+                    .to_lowercase();
+                let user_id = self.app.get_user_id_by_name(&user_login).await?;
+                self.app
+                    .check_opted_out(&privmsg.channel_id, Some(&user_id))?;
+
+                let stats = get_user_stats(
+                    &self.app.db,
+                    &privmsg.channel_id,
+                    user_id,
+                    Some(user_login.clone()),
+                    LogRangeParams {
+                        from: None,
+                        to: None,
+                    },
+                )
+                .await?;
+                format!(
+                    "{user_login} has sent {} messages in this channel",
+                    stats.message_count
+                )
+            }
+            "optin" => self.optin_user(&args, role, &privmsg.sender.id).await?,
+            "optout" => {
+                self.optout_user(&args, role, &privmsg.sender.id).await?;
+                return Ok(None);
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some(reply))
+    }
+
+    async fn optout_user(&self, args: &[&str], role: Role, sender_id: &str) -> anyhow::Result<()> {
+        let arg = args.first().context("No optout code provided")?;
+        if self.app.optout_codes.remove(*arg).is_some() {
+            self.app.optout_user(sender_id).await?;
+
+            Ok(())
+        } else if role == Role::Admin {
+            let user_id = self.app.get_user_id_by_name(arg).await?;
+
+            self.app.optout_user(&user_id).await?;
+
+            Ok(())
+        } else {
+            Err(anyhow!("Invalid optout code"))
+        }
+    }
+
+    /// Users can opt themselves back in with a code, admins can opt in anyone
+    async fn optin_user(
+        &self,
+        args: &[&str],
+        role: Role,
+        sender_id: &str,
+    ) -> anyhow::Result<String> {
+        let arg = args.first().context("No optin code provided")?;
+        if self.app.optin_codes.remove(*arg).is_some() {
+            self.app.optin_user(sender_id).await?;
+
+            Ok("You have been opted in, your messages will be logged again".to_owned())
+        } else if role == Role::Admin {
+            let user_id = self.app.get_user_id_by_name(arg).await?;
+
+            if self.app.optin_user(&user_id).await? {
+                Ok(format!("{arg} has been opted in"))
+            } else {
+                Ok(format!("{arg} was not opted out"))
+            }
+        } else {
+            Err(anyhow!("Invalid optin code"))
+        }
+    }
+
+    async fn update_channels<C: LoginCredentials + Clone>(
+        &self,
+        shards: &Shards<C>,
+        channels: &[&str],
+        action: ChannelAction,
+    ) -> anyhow::Result<()> {
+        if channels.is_empty() {
+            return Err(anyhow!("no channels specified"));
+        }
+
+        let channels = self
+            .app
+            .get_users(
+                vec![],
+                channels.iter().map(ToString::to_string).collect(),
+                false,
+            )
+            .await?;
+
+        {
+            let mut config_channels = self.app.config.channels.write().unwrap();
+            let mut shards = shards.lock().unwrap();
+
+            for (channel_id, channel_name) in channels {
+                match action {
+                    ChannelAction::Join => {
+                        info!("Joining channel {channel_name}");
+                        config_channels.insert(channel_id);
+                        shards.join(channel_name)?;
+                    }
+                    ChannelAction::Part => {
+                        info!("Parting channel {channel_name}");
+                        config_channels.remove(&channel_id);
+                        for moved_channel in shards.part(&channel_name) {
+                            self.connections.moved(moved_channel);
+                        }
+                    }
+                }
+            }
+        }
+
+        self.app.config.save()?;
+
+        Ok(())
+    }
+}
+
+fn require_role(role: Role, required: Role) -> anyhow::Result<()> {
+    if role >= required {
+        Ok(())
+    } else {
+        Err(anyhow!("{required:?} role is required, sender is {role:?}"))
+    }
+}
+
+enum ChannelAction {
+    Join,
+    Part,
+}
@@ -0,0 +1,88 @@
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+use std::time::Duration;
+use tracing::{debug, warn};
+use twitch_irc::message::IRCMessage;
+
+const REQUEST_TIMEOUT_SECONDS: u64 = 15;
+/// Tags added by the recent-messages service which are not present on live messages
+const SERVICE_TAGS: &[&str] = &["historical", "rm-received-ts", "rm-deleted"];
+
+#[derive(Deserialize)]
+struct RecentMessagesResponse {
+    messages: Vec<String>,
+    error: Option<String>,
+}
+
+/// A message fetched from the recent-messages service
+pub struct RecentMessage {
+    pub irc_message: IRCMessage,
+    /// When the service received the message
+    pub received_at: Option<u64>,
+}
+
+#[derive(Clone)]
+pub struct RecentMessagesClient {
+    http_client: reqwest::Client,
+    url: String,
+}
+
+impl RecentMessagesClient {
+    pub fn new(url: String) -> Self {
+        let http_client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(REQUEST_TIMEOUT_SECONDS))
+            .build()
+            .expect("Could not build HTTP client");
+        Self { http_client, url }
+    }
+
+    pub async fn fetch(&self, channel_login: &str) -> anyhow::Result<Vec<RecentMessage>> {
+        let url = self.url.replace("{channel}", channel_login);
+        debug!("Fetching recent messages from {url}");
+
+        let body = self
+            .http_client
+            .get(&url)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let response: RecentMessagesResponse =
+            serde_json::from_str(&body).context("Invalid recent-messages response")?;
+
+        if let Some(error) = response.error {
+            return Err(anyhow!(
+                "Recent-messages service returned an error: {error}"
+            ));
+        }
+
+        let messages = response
+            .messages
+            .iter()
+            .filter_map(|raw| match IRCMessage::parse(raw) {
+                Ok(mut irc_message) => {
+                    let received_at = irc_message
+                        .tags
+                        .0
+                        .get("rm-received-ts")
+                        .and_then(|value| value.as_deref())
+                        .and_then(|value| value.parse().ok());
+                    for tag in SERVICE_TAGS {
+                        irc_message.tags.0.remove(*tag);
+                    }
+
+                    Some(RecentMessage {
+                        irc_message,
+                        received_at,
+                    })
+                }
+                Err(err) => {
+                    warn!("Could not parse recent message {raw}: {err}");
+                    None
+                }
+            })
+            .collect();
+        Ok(messages)
+    }
+}
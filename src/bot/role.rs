@@ -0,0 +1,98 @@
+use crate::config::Config;
+use twitch_irc::message::PrivmsgMessage;
+
+/// Permission level of a command sender, ordered from least to most privileged
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Role {
+    User,
+    /// Moderator of the channel the command was sent in
+    Moderator,
+    /// Owner of the channel the command was sent in
+    Broadcaster,
+    /// Configured in `admins`, applies to all channels
+    Admin,
+}
+
+impl Role {
+    pub fn of_sender(msg: &PrivmsgMessage, config: &Config) -> Self {
+        let has_badge = |name: &str| msg.badges.iter().any(|badge| badge.name == name);
+
+        if config.admins.contains(&msg.sender.login) {
+            Role::Admin
+        } else if msg.sender.id == msg.channel_id || has_badge("broadcaster") {
+            Role::Broadcaster
+        } else if has_badge("moderator") {
+            Role::Moderator
+        } else {
+            Role::User
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Role;
+    use crate::config::Config;
+    use serde_json::json;
+    use twitch_irc::message::{IRCMessage, PrivmsgMessage};
+
+    fn config() -> Config {
+        serde_json::from_value(json!({
+            "clickhouseUrl": "http://localhost:8123",
+            "clickhouseDb": "rustlog",
+            "channels": [],
+            "clientID": "",
+            "clientSecret": "",
+            "admins": ["admin"],
+        }))
+        .unwrap()
+    }
+
+    fn privmsg(login: &str, user_id: &str, badges: &str) -> PrivmsgMessage {
+        let raw = format!("@badge-info=;badges={badges};color=;display-name={login};emotes=;id=272e342c-5864-4c59-b730-25908cdb7f57;room-id=22484632;tmi-sent-ts=1709251274940;user-id={user_id} :{login}!{login}@{login}.tmi.twitch.tv PRIVMSG #forsen :!status");
+        PrivmsgMessage::try_from(IRCMessage::parse(&raw).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn admin_from_config() {
+        assert_eq!(
+            Role::of_sender(&privmsg("admin", "1", ""), &config()),
+            Role::Admin
+        );
+    }
+
+    #[test]
+    fn broadcaster_by_id_or_badge() {
+        assert_eq!(
+            Role::of_sender(&privmsg("forsen", "22484632", ""), &config()),
+            Role::Broadcaster
+        );
+        assert_eq!(
+            Role::of_sender(&privmsg("forsen", "1", "broadcaster/1"), &config()),
+            Role::Broadcaster
+        );
+    }
+
+    #[test]
+    fn moderator_badge() {
+        assert_eq!(
+            Role::of_sender(
+                &privmsg("user", "1", "moderator/1,subscriber/12"),
+                &config()
+            ),
+            Role::Moderator
+        );
+    }
+
+    #[test]
+    fn other_badges_are_users() {
+        assert_eq!(
+            Role::of_sender(&privmsg("user", "1", "vip/1,subscriber/12"), &config()),
+            Role::User
+        );
+        assert_eq!(
+            Role::of_sender(&privmsg("user", "1", ""), &config()),
+            Role::User
+        );
+    }
+}
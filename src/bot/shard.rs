@@ -0,0 +1,212 @@
+use anyhow::Context;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_int_counter_vec, register_int_gauge, register_int_gauge_vec, IntCounterVec, IntGauge,
+    IntGaugeVec,
+};
+use std::collections::HashSet;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tracing::{debug, info};
+use twitch_irc::{
+    login::LoginCredentials, message::ServerMessage, ClientConfig, SecureTCPTransport,
+    TwitchIRCClient,
+};
+
+pub type TwitchClient<C> = TwitchIRCClient<SecureTCPTransport, C>;
+
+lazy_static! {
+    static ref SHARDS_GAUGE: IntGauge =
+        register_int_gauge!("rustlog_shards", "How many IRC shards are running").unwrap();
+    static ref SHARD_CHANNELS_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "rustlog_shard_channels",
+        "How many channels are assigned to a shard",
+        &["shard"]
+    )
+    .unwrap();
+    static ref SHARD_MESSAGES_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "rustlog_shard_messages_received",
+        "How many messages were received by a shard",
+        &["shard"]
+    )
+    .unwrap();
+}
+
+struct Shard<C: LoginCredentials> {
+    id: usize,
+    client: TwitchClient<C>,
+    channels: HashSet<String>,
+}
+
+/// Spreads channels across multiple IRC clients, each of which uses a single connection
+pub struct ShardManager<C: LoginCredentials> {
+    login_credentials: C,
+    channels_per_shard: usize,
+    shards: Vec<Shard<C>>,
+    next_shard_id: usize,
+    message_tx: UnboundedSender<ServerMessage>,
+}
+
+impl<C: LoginCredentials + Clone> ShardManager<C> {
+    /// Returns the manager and a receiver with messages from all shards
+    pub fn new(
+        login_credentials: C,
+        channels_per_shard: usize,
+    ) -> (Self, UnboundedReceiver<ServerMessage>) {
+        let (message_tx, message_rx) = unbounded_channel();
+        let manager = Self {
+            login_credentials,
+            channels_per_shard: channels_per_shard.max(1),
+            shards: Vec::new(),
+            next_shard_id: 0,
+            message_tx,
+        };
+        (manager, message_rx)
+    }
+
+    pub fn join(&mut self, channel_login: String) -> anyhow::Result<()> {
+        if let Some(shard) = self
+            .shards
+            .iter()
+            .find(|shard| shard.channels.contains(&channel_login))
+        {
+            // Joining an already joined channel is a no-op for the client
+            return shard
+                .client
+                .join(channel_login)
+                .context("Invalid channel name");
+        }
+
+        let shard_index = self.find_free_shard();
+        let shard = &mut self.shards[shard_index];
+        shard
+            .client
+            .join(channel_login.clone())
+            .context("Invalid channel name")?;
+        shard.channels.insert(channel_login);
+
+        SHARD_CHANNELS_GAUGE
+            .with_label_values(&[&shard.id.to_string()])
+            .set(shard.channels.len() as i64);
+
+        Ok(())
+    }
+
+    /// Returns the channels which were moved to another shard, as they were briefly disconnected
+    pub fn part(&mut self, channel_login: &str) -> Vec<String> {
+        if let Some(shard) = self
+            .shards
+            .iter_mut()
+            .find(|shard| shard.channels.contains(channel_login))
+        {
+            shard.client.part(channel_login.to_owned());
+            shard.channels.remove(channel_login);
+
+            SHARD_CHANNELS_GAUGE
+                .with_label_values(&[&shard.id.to_string()])
+                .set(shard.channels.len() as i64);
+        }
+
+        self.rebalance()
+    }
+
+    /// Returns the client of the shard which has joined the channel
+    pub fn client_for(&self, channel_login: &str) -> Option<TwitchClient<C>> {
+        self.shards
+            .iter()
+            .find(|shard| shard.channels.contains(channel_login))
+            .map(|shard| shard.client.clone())
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Moves channels out of the least used shards when they fit into fewer shards
+    fn rebalance(&mut self) -> Vec<String> {
+        let mut moved_channels = Vec::new();
+        let channel_count: usize = self.shards.iter().map(|shard| shard.channels.len()).sum();
+        let needed_shards = channel_count.div_ceil(self.channels_per_shard);
+
+        while self.shards.len() > needed_shards {
+            let (smallest_index, _) = self
+                .shards
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, shard)| shard.channels.len())
+                .expect("Shards list cannot be empty");
+            let shard = self.shards.remove(smallest_index);
+
+            info!(
+                "Removing shard {} and moving its {} channels",
+                shard.id,
+                shard.channels.len()
+            );
+            let _ = SHARD_CHANNELS_GAUGE.remove_label_values(&[&shard.id.to_string()]);
+            let _ = SHARD_MESSAGES_COUNTER.remove_label_values(&[&shard.id.to_string()]);
+
+            // Dropping the client closes its connection
+            let Shard { channels, .. } = shard;
+            for channel_login in channels {
+                match self.join(channel_login.clone()) {
+                    Ok(()) => moved_channels.push(channel_login),
+                    Err(err) => debug!("Could not move channel to another shard: {err}"),
+                }
+            }
+        }
+
+        SHARDS_GAUGE.set(self.shards.len() as i64);
+        moved_channels
+    }
+
+    /// Returns the index of the least used shard that has space for another channel, creating a new one if needed
+    fn find_free_shard(&mut self) -> usize {
+        let free_shard = self
+            .shards
+            .iter()
+            .enumerate()
+            .filter(|(_, shard)| shard.channels.len() < self.channels_per_shard)
+            .min_by_key(|(_, shard)| shard.channels.len())
+            .map(|(i, _)| i);
+
+        match free_shard {
+            Some(i) => i,
+            None => {
+                self.spawn_shard();
+                self.shards.len() - 1
+            }
+        }
+    }
+
+    fn spawn_shard(&mut self) {
+        let id = self.next_shard_id;
+        self.next_shard_id += 1;
+
+        let mut client_config = ClientConfig::new_simple(self.login_credentials.clone());
+        client_config.max_channels_per_connection = self.channels_per_shard;
+
+        let (mut receiver, client) = TwitchClient::<C>::new(client_config);
+
+        let message_tx = self.message_tx.clone();
+        tokio::spawn(async move {
+            let shard_label = id.to_string();
+            // Ends when the client gets dropped
+            while let Some(msg) = receiver.recv().await {
+                SHARD_MESSAGES_COUNTER
+                    .with_label_values(&[&shard_label])
+                    .inc();
+                if message_tx.send(msg).is_err() {
+                    break;
+                }
+            }
+            debug!("Shard {id} receiver closed");
+        });
+
+        info!("Started shard {id}");
+        self.shards.push(Shard {
+            id,
+            client,
+            channels: HashSet::new(),
+        });
+        SHARDS_GAUGE.set(self.shards.len() as i64);
+    }
+}
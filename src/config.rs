@@ -1,9 +1,16 @@
-use anyhow::Context;
-use dashmap::DashMap;
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use dashmap::{DashMap, DashSet};
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::{collections::HashSet, sync::RwLock};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{Arc, RwLock},
+};
 use tracing::info;
+use twitch_irc::login::{TokenStorage, UserAccessToken};
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -14,15 +21,63 @@ pub struct Config {
     pub clickhouse_password: Option<String>,
     #[serde(default = "clickhouse_flush_interval")]
     pub clickhouse_flush_interval: u64,
+    /// Flush as soon as this many messages are buffered, without waiting for the interval
+    pub clickhouse_flush_max_messages: Option<usize>,
+    /// Flush as soon as the buffered messages reach this estimated size in bytes, without waiting for the interval
+    pub clickhouse_flush_max_bytes: Option<u64>,
+    /// Directory where unwritten messages are kept, so they are not lost if the database is unavailable
+    /// or the process crashes. Disabled if not specified
+    pub spool_path: Option<std::path::PathBuf>,
+    /// Maximum size of the spool in bytes
+    #[serde(default = "default_spool_max_size")]
+    pub spool_max_size: u64,
+    /// Maximum amount of messages kept in memory until they are written. Unlimited if not specified
+    pub buffer_max_messages: Option<usize>,
+    /// Maximum estimated size of the messages kept in memory in bytes. Unlimited if not specified
+    pub buffer_max_bytes: Option<u64>,
+    /// What happens to new messages when the buffer is full
+    #[serde(default)]
+    pub buffer_overflow_policy: OverflowPolicy,
+    /// How many messages can be queued up for the write buffer before sources have to wait
+    #[serde(default = "default_buffer_queue_size")]
+    pub buffer_queue_size: usize,
     #[serde(default = "default_listen_address")]
     pub listen_address: String,
     pub channels: RwLock<HashSet<String>>,
+    /// How many channels are joined on a single IRC connection
+    #[serde(default = "default_channels_per_connection")]
+    pub channels_per_connection: usize,
     #[serde(rename = "clientID")]
     pub client_id: String,
     pub client_secret: String,
     pub admins: Vec<String>,
+    /// Login name of the bot account. Fetched from the token if not specified
+    pub bot_login: Option<String>,
+    /// User access token of the bot account. The bot connects anonymously if not specified
+    #[serde(default)]
+    pub bot_token: RwLock<Option<BotToken>>,
+    /// URL of a recent-messages service used to backfill messages after joining a channel.
+    /// `{channel}` is replaced with the channel login
+    pub recent_messages_url: Option<String>,
     #[serde(default)]
     pub opt_out: DashMap<String, bool>,
+    /// Channel ids which are joined, but their messages are not logged
+    #[serde(default)]
+    pub paused_channels: DashSet<String>,
+    /// Message types which are logged. All types are logged if not specified
+    pub message_types: Option<Vec<String>>,
+    /// Per-channel overrides of `message_types`, keyed by channel id
+    #[serde(default)]
+    pub channel_message_types: HashMap<String, Vec<String>>,
+    /// User ids ignored in all channels, such as bots
+    #[serde(default)]
+    pub ignored_users: HashSet<String>,
+    /// User ids ignored in specific channels, keyed by channel id
+    #[serde(default)]
+    pub channel_ignored_users: HashMap<String, HashSet<String>>,
+    /// Whether messages from ignored users are stored. They are still excluded from stats
+    #[serde(default)]
+    pub store_ignored_users: bool,
     #[serde(rename = "adminAPIKey")]
     pub admin_api_key: Option<String>,
     #[serde(skip)]
@@ -38,6 +93,23 @@ impl Config {
         Ok(s)
     }
 
+    pub fn is_user_ignored(&self, channel_id: &str, user_id: &str) -> bool {
+        self.ignored_users.contains(user_id)
+            || self
+                .channel_ignored_users
+                .get(channel_id)
+                .is_some_and(|users| users.contains(user_id))
+    }
+
+    /// Returns all user ids which are ignored in the channel
+    pub fn ignored_users_in(&self, channel_id: &str) -> Vec<String> {
+        let mut user_ids: Vec<String> = self.ignored_users.iter().cloned().collect();
+        if let Some(channel_users) = self.channel_ignored_users.get(channel_id) {
+            user_ids.extend(channel_users.iter().cloned());
+        }
+        user_ids
+    }
+
     pub fn save(&self) -> anyhow::Result<()> {
         info!("Updating config");
         let json = serde_json::to_string_pretty(self)?;
@@ -47,10 +119,98 @@ impl Config {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum OverflowPolicy {
+    /// Write new messages only to the spool, they are inserted from disk with the next batch
+    Spill,
+    /// Discard the oldest buffered messages
+    #[default]
+    DropOldest,
+    /// Stop accepting new messages until the buffer has been written
+    Block,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BotToken {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub created_at: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Persists refreshed bot tokens in the config file
+#[derive(Clone)]
+pub struct ConfigTokenStorage {
+    config: Arc<Config>,
+}
+
+impl ConfigTokenStorage {
+    pub fn new(config: Arc<Config>) -> Self {
+        Self { config }
+    }
+}
+
+impl fmt::Debug for ConfigTokenStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConfigTokenStorage").finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl TokenStorage for ConfigTokenStorage {
+    type LoadError = anyhow::Error;
+    type UpdateError = anyhow::Error;
+
+    async fn load_token(&mut self) -> anyhow::Result<UserAccessToken> {
+        let token = self
+            .config
+            .bot_token
+            .read()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| anyhow!("No bot token configured"))?;
+
+        let now = Utc::now();
+        Ok(UserAccessToken {
+            access_token: token.access_token,
+            refresh_token: token.refresh_token,
+            created_at: token.created_at.unwrap_or(now),
+            // A token with an unknown expiry time is refreshed immediately to find out its lifetime
+            expires_at: Some(token.expires_at.unwrap_or(now)),
+        })
+    }
+
+    async fn update_token(&mut self, token: &UserAccessToken) -> anyhow::Result<()> {
+        info!("Bot token has been refreshed");
+
+        *self.config.bot_token.write().unwrap() = Some(BotToken {
+            access_token: token.access_token.clone(),
+            refresh_token: token.refresh_token.clone(),
+            created_at: Some(token.created_at),
+            expires_at: token.expires_at,
+        });
+        self.config.save()
+    }
+}
+
 fn default_listen_address() -> String {
     String::from("0.0.0.0:8025")
 }
 
+fn default_spool_max_size() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_buffer_queue_size() -> usize {
+    1000
+}
+
+fn default_channels_per_connection() -> usize {
+    90
+}
+
 fn clickhouse_flush_interval() -> u64 {
     10
 }
@@ -0,0 +1,279 @@
+use super::schema::StructuredMessage;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ops::Range,
+};
+
+/// Buffered messages in arrival order, indexed by channel and user so reads are range lookups instead of scans.
+///
+/// Messages are keyed by an arrival sequence number which never changes,
+/// so removing or prepending messages only touches the index entries of those messages.
+#[derive(Default, Clone)]
+pub struct MessageIndex {
+    messages: BTreeMap<i64, StructuredMessage<'static>>,
+    channels: HashMap<String, ChannelIndex>,
+}
+
+/// Sequence numbers of messages, ordered by timestamp
+type Positions = BTreeSet<(u64, i64)>;
+
+#[derive(Default, Clone)]
+struct ChannelIndex {
+    messages: Positions,
+    users: HashMap<String, Positions>,
+}
+
+impl MessageIndex {
+    pub fn push(&mut self, message: StructuredMessage<'static>) {
+        let sequence = self
+            .messages
+            .last_key_value()
+            .map_or(0, |(sequence, _)| sequence + 1);
+        add_to_index(&mut self.channels, &message, sequence);
+        self.messages.insert(sequence, message);
+    }
+
+    /// Messages of a channel (and user, if specified) in the time range, ordered by timestamp
+    pub fn range<'a>(
+        &'a self,
+        channel_id: &str,
+        user_id: Option<&str>,
+        time_range: Range<u64>,
+    ) -> impl Iterator<Item = &'a StructuredMessage<'static>> + 'a {
+        let positions = self
+            .channels
+            .get(channel_id)
+            .and_then(|channel| match user_id {
+                Some(user_id) => channel.users.get(user_id),
+                None => Some(&channel.messages),
+            });
+
+        positions
+            .into_iter()
+            // Inverted ranges would make the set panic
+            .filter(move |_| time_range.start < time_range.end)
+            .flat_map(move |positions| {
+                positions.range((time_range.start, i64::MIN)..(time_range.end, i64::MIN))
+            })
+            .map(|(_, sequence)| &self.messages[sequence])
+    }
+
+    /// All messages in arrival order
+    pub fn messages(&self) -> impl DoubleEndedIterator<Item = &StructuredMessage<'static>> {
+        self.messages.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn retain(&mut self, mut f: impl FnMut(&StructuredMessage<'static>) -> bool) {
+        let channels = &mut self.channels;
+        self.messages.retain(|sequence, message| {
+            let keep = f(message);
+            if !keep {
+                remove_from_index(channels, message, *sequence);
+            }
+            keep
+        });
+    }
+
+    /// Removes the oldest messages by arrival and returns them
+    pub fn drain_front(&mut self, count: usize) -> Vec<StructuredMessage<'static>> {
+        let mut drained = Vec::with_capacity(count.min(self.messages.len()));
+        while drained.len() < count {
+            let Some((sequence, message)) = self.messages.pop_first() else {
+                break;
+            };
+            remove_from_index(&mut self.channels, &message, sequence);
+            drained.push(message);
+        }
+        drained
+    }
+
+    /// Puts all messages of `other` before the messages of this index
+    pub fn prepend(&mut self, other: MessageIndex) {
+        let first = self
+            .messages
+            .first_key_value()
+            .map_or(0, |(sequence, _)| *sequence);
+
+        let start = first - other.messages.len() as i64;
+        for (sequence, message) in (start..first).zip(other.messages.into_values()) {
+            add_to_index(&mut self.channels, &message, sequence);
+            self.messages.insert(sequence, message);
+        }
+    }
+}
+
+fn add_to_index(
+    channels: &mut HashMap<String, ChannelIndex>,
+    message: &StructuredMessage,
+    sequence: i64,
+) {
+    let key = (message.timestamp, sequence);
+
+    let channel = channels.entry(message.channel_id.to_string()).or_default();
+    channel.messages.insert(key);
+    channel
+        .users
+        .entry(message.user_id.to_string())
+        .or_default()
+        .insert(key);
+}
+
+fn remove_from_index(
+    channels: &mut HashMap<String, ChannelIndex>,
+    message: &StructuredMessage,
+    sequence: i64,
+) {
+    let key = (message.timestamp, sequence);
+
+    let Some(channel) = channels.get_mut(message.channel_id.as_ref()) else {
+        return;
+    };
+    channel.messages.remove(&key);
+    if let Some(user) = channel.users.get_mut(message.user_id.as_ref()) {
+        user.remove(&key);
+        if user.is_empty() {
+            channel.users.remove(message.user_id.as_ref());
+        }
+    }
+    if channel.messages.is_empty() {
+        channels.remove(message.channel_id.as_ref());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MessageIndex;
+    use crate::db::schema::{StructuredMessage, UnstructuredMessage};
+    use pretty_assertions::assert_eq;
+    use std::ops::Range;
+
+    fn message(channel_id: &str, user_id: &str, timestamp: u64) -> StructuredMessage<'static> {
+        let raw = format!("@room-id={channel_id};user-id={user_id};tmi-sent-ts={timestamp} :user!user@user.tmi.twitch.tv PRIVMSG #channel :hello");
+        let unstructured = UnstructuredMessage {
+            channel_id,
+            user_id,
+            timestamp,
+            raw: &raw,
+        };
+        StructuredMessage::from_unstructured(&unstructured)
+            .unwrap()
+            .into_owned()
+    }
+
+    fn index(messages: &[(&str, &str, u64)]) -> MessageIndex {
+        let mut index = MessageIndex::default();
+        for (channel_id, user_id, timestamp) in messages {
+            index.push(message(channel_id, user_id, *timestamp));
+        }
+        index
+    }
+
+    fn timestamps<'a>(messages: impl Iterator<Item = &'a StructuredMessage<'static>>) -> Vec<u64> {
+        messages.map(|msg| msg.timestamp).collect()
+    }
+
+    #[test]
+    fn empty() {
+        let mut index = MessageIndex::default();
+
+        assert_eq!(index.range("1", None, 0..u64::MAX).count(), 0);
+        assert!(index.drain_front(5).is_empty());
+        index.prepend(MessageIndex::default());
+        assert_eq!(index.len(), 0);
+
+        index.prepend(self::index(&[("1", "a", 10)]));
+        assert_eq!(timestamps(index.range("1", None, 0..u64::MAX)), vec![10]);
+    }
+
+    #[test]
+    fn range_is_ordered_by_timestamp() {
+        // Backfilled messages arrive out of order
+        let index = index(&[
+            ("1", "a", 30),
+            ("1", "b", 10),
+            ("2", "a", 20),
+            ("1", "a", 20),
+            ("1", "a", 20),
+        ]);
+
+        assert_eq!(
+            timestamps(index.range("1", None, 0..u64::MAX)),
+            vec![10, 20, 20, 30]
+        );
+        assert_eq!(
+            timestamps(index.range("1", Some("a"), 20..30)),
+            vec![20, 20]
+        );
+        assert_eq!(index.range("2", Some("b"), 0..u64::MAX).count(), 0);
+        assert_eq!(timestamps(index.messages()), vec![30, 10, 20, 20, 20]);
+    }
+
+    #[test]
+    fn inverted_range() {
+        let index = index(&[("1", "a", 10), ("1", "a", 20)]);
+
+        // Such as a request with `from` after `to`
+        let inverted = Range { start: 20, end: 10 };
+        assert_eq!(index.range("1", None, inverted).count(), 0);
+        assert_eq!(index.range("1", Some("a"), 10..10).count(), 0);
+    }
+
+    #[test]
+    fn drain_front() {
+        let mut index = index(&[("1", "a", 10), ("1", "b", 20), ("2", "a", 30)]);
+
+        let drained = index.drain_front(2);
+
+        assert_eq!(timestamps(drained.iter()), vec![10, 20]);
+        assert_eq!(index.range("1", None, 0..u64::MAX).count(), 0);
+        assert_eq!(
+            timestamps(index.range("2", Some("a"), 0..u64::MAX)),
+            vec![30]
+        );
+
+        index.push(message("1", "a", 40));
+        assert_eq!(timestamps(index.messages()), vec![30, 40]);
+        assert_eq!(index.drain_front(10).len(), 2);
+        assert_eq!(index.len(), 0);
+    }
+
+    #[test]
+    fn prepend_overlapping() {
+        let mut index = index(&[("1", "a", 20), ("1", "a", 40)]);
+
+        index.prepend(self::index(&[
+            ("1", "a", 10),
+            ("1", "b", 30),
+            ("1", "a", 40),
+        ]));
+
+        assert_eq!(timestamps(index.messages()), vec![10, 30, 40, 20, 40]);
+        assert_eq!(
+            timestamps(index.range("1", Some("a"), 0..u64::MAX)),
+            vec![10, 20, 40, 40]
+        );
+
+        // Prepending again goes before the previously prepended messages
+        index.prepend(self::index(&[("1", "b", 5)]));
+        assert_eq!(timestamps(index.drain_front(2).iter()), vec![5, 10]);
+        assert_eq!(
+            timestamps(index.range("1", Some("b"), 0..u64::MAX)),
+            vec![30]
+        );
+    }
+
+    #[test]
+    fn retain() {
+        let mut index = index(&[("1", "a", 10), ("1", "b", 20), ("1", "a", 30)]);
+
+        index.retain(|msg| msg.user_id != "a");
+
+        assert_eq!(timestamps(index.messages()), vec![20]);
+        assert_eq!(index.range("1", Some("a"), 0..u64::MAX).count(), 0);
+        assert_eq!(timestamps(index.range("1", None, 0..u64::MAX)), vec![20]);
+    }
+}
@@ -0,0 +1,155 @@
+use super::{migratable::Migratable, username_history::CREATE_USERNAME_HISTORY_MV};
+use anyhow::{bail, Context};
+use std::{
+    env,
+    time::{Duration, Instant},
+};
+use tokio::time::sleep;
+use tracing::{error, info};
+
+const MUTATION_POLL_INTERVAL_SECONDS: u64 = 10;
+
+/// Recreates `message_structured` as a `ReplacingMergeTree`, so messages which were stored more than once
+/// (insert retries, multiple instances logging the same channel, repeated imports) get merged into one row
+pub struct DeduplicationMigration<'a> {
+    pub db_name: &'a str,
+}
+
+impl<'a> Migratable<'a> for DeduplicationMigration<'a> {
+    async fn run(&self, db: &'a clickhouse::Client) -> anyhow::Result<()> {
+        let partitions = db
+            .query("SELECT DISTINCT partition FROM system.parts WHERE database = ? AND table = 'message_structured' AND active ORDER BY partition ASC")
+            .bind(self.db_name)
+            .fetch_all::<String>()
+            .await
+            .context("Could not fetch partition list")?;
+
+        if partitions.len() > 1
+            && env::var("RUSTLOG_ACKNOWLEDGE_DEDUPLICATION_MIGRATION").as_deref() != Ok("1")
+        {
+            bail!(
+                "The current version of rustlog needs to copy all messages into a new table which removes duplicate messages. This process can take from a few minutes to several hours depending on the database size. \
+                The database will temporarily double in size in the process. \
+                Set the environment variable RUSTLOG_ACKNOWLEDGE_DEDUPLICATION_MIGRATION=1 to confirm and run the migration, or downgrade to an older version if you don't want to run it right now."
+            );
+        }
+
+        // Projections on deduplicating tables have to be rebuilt on merges, newer versions refuse to create them otherwise
+        let projection_mode_supported = db
+            .query("SELECT count() FROM system.merge_tree_settings WHERE name = 'deduplicate_merge_projection_mode'")
+            .fetch_one::<u64>()
+            .await?
+            > 0;
+        let settings = if projection_mode_supported {
+            "SETTINGS deduplicate_merge_projection_mode = 'rebuild'"
+        } else {
+            ""
+        };
+
+        db.query("DROP TABLE IF EXISTS message_structured_dedup")
+            .execute()
+            .await?;
+        db.query(&format!(
+            "
+CREATE TABLE message_structured_dedup
+(
+    `channel_id` LowCardinality(String) CODEC(ZSTD(8)),
+    `channel_login` LowCardinality(String) CODEC(ZSTD(8)),
+    `timestamp` DateTime64(3) CODEC(T64, ZSTD(5)),
+    `id` UUID CODEC(ZSTD(1)),
+    `message_type` UInt8 CODEC(ZSTD(8)),
+    `user_id` String CODEC(ZSTD(8)),
+    `user_login` String CODEC(ZSTD(8)),
+    `display_name` String CODEC(ZSTD(8)),
+    `color` Nullable(UInt32) CODEC(ZSTD(8)),
+    `user_type` LowCardinality(String) CODEC(ZSTD(8)),
+    `badges` Array(LowCardinality(String)) CODEC(ZSTD(8)),
+    `badge_info` String CODEC(ZSTD(8)),
+    `client_nonce` String CODEC(ZSTD(1)),
+    `emotes` String CODEC(ZSTD(8)),
+    `automod_flags` String CODEC(ZSTD(8)),
+    `text` String CODEC(ZSTD(8)),
+    `message_flags` UInt16 CODEC(ZSTD(8)),
+    `extra_tags` Map(LowCardinality(String), String) CODEC(ZSTD(8)),
+    PROJECTION channel_log_dates
+    (
+        SELECT
+            channel_id,
+            toDateTime(toStartOfDay(timestamp)) AS date
+        GROUP BY
+            channel_id,
+            date
+    )
+)
+ENGINE = ReplacingMergeTree
+PARTITION BY toYYYYMM(timestamp)
+ORDER BY (channel_id, user_id, timestamp, message_type, id)
+{settings}
+        "
+        ))
+        .execute()
+        .await?;
+
+        // Messages of opted out users which are still pending deletion would be copied over otherwise
+        wait_for_mutations(db, self.db_name).await?;
+
+        info!(
+            "Copying {} partitions into deduplicated table",
+            partitions.len()
+        );
+        let started_at = Instant::now();
+
+        for partition in &partitions {
+            info!("Copying partition {partition}");
+            db.query("INSERT INTO message_structured_dedup SELECT * FROM message_structured WHERE toYYYYMM(timestamp) = ?")
+                .bind(partition)
+                .execute()
+                .await
+                .with_context(|| format!("Could not copy partition {partition}"))?;
+        }
+
+        info!("Copied partitions in {:?}", started_at.elapsed());
+
+        // The view reads from the table by name, so it has to be recreated after the swap
+        db.query("DROP VIEW IF EXISTS username_history_mv")
+            .execute()
+            .await?;
+        db.query("RENAME TABLE message_structured TO message_structured_old, message_structured_dedup TO message_structured")
+            .execute()
+            .await?;
+        db.query(CREATE_USERNAME_HISTORY_MV).execute().await?;
+
+        info!("Dropping old table");
+        if let Err(err) = db
+            .query("DROP TABLE message_structured_old")
+            .execute()
+            .await
+        {
+            error!("Could not drop old table: {err}");
+            error!("Drop it manually with `DROP TABLE message_structured_old` to save on space")
+        }
+
+        Ok(())
+    }
+}
+
+async fn wait_for_mutations(db: &clickhouse::Client, db_name: &str) -> anyhow::Result<()> {
+    loop {
+        let (pending, failed) = db
+            .query("SELECT count(), countIf(latest_fail_reason != '') FROM system.mutations WHERE database = ? AND table = 'message_structured' AND NOT is_done")
+            .bind(db_name)
+            .fetch_one::<(u64, u64)>()
+            .await
+            .context("Could not fetch pending mutations")?;
+
+        if pending == 0 {
+            return Ok(());
+        }
+        if failed > 0 {
+            bail!("{failed} mutations on message_structured are failing, they have to be fixed or killed before the migration can run. See system.mutations for details");
+        }
+
+        info!("Waiting for {pending} pending mutations on message_structured to finish before copying");
+        sleep(Duration::from_secs(MUTATION_POLL_INTERVAL_SECONDS)).await;
+    }
+}
@@ -1,9 +1,13 @@
+mod deduplication;
 mod migratable;
+mod skip_index;
 mod structured;
 mod username_history;
 
 use crate::Result;
 use clickhouse::Client;
+use deduplication::DeduplicationMigration;
+use skip_index::SkipIndexMigration;
 use structured::StructuredMigration;
 use tracing::{debug, info};
 use username_history::UsernameHistoryMigration;
@@ -75,6 +79,66 @@ String CODEC(ZSTD(10))
 
     run_migration(db, "7_username_history", UsernameHistoryMigration).await?;
 
+    run_migration(
+        db,
+        "8_connection_event",
+        "
+CREATE TABLE connection_event
+(
+    channel_id LowCardinality(String),
+    timestamp DateTime64(3) CODEC(T64, ZSTD(5)),
+    event_type UInt8
+)
+ENGINE = MergeTree
+ORDER BY (channel_id, timestamp)",
+    )
+    .await?;
+
+    run_migration(
+        db,
+        "9_opt_out_event",
+        "
+CREATE TABLE opt_out_event
+(
+    user_id String,
+    timestamp DateTime64(3) CODEC(T64, ZSTD(5)),
+    event_type UInt8
+)
+ENGINE = MergeTree
+ORDER BY (user_id, timestamp)",
+    )
+    .await?;
+
+    run_migration(
+        db,
+        "10_deduplicate_message_structured",
+        DeduplicationMigration { db_name },
+    )
+    .await?;
+
+    // Used by text searches, has to match the expression they filter on
+    run_migration(
+        db,
+        "11_text_ngram_index",
+        SkipIndexMigration {
+            db_name,
+            name: "text_ngram",
+            definition: "lowerUTF8(text) TYPE ngrambf_v1(3, 65536, 2, 0) GRANULARITY 1",
+        },
+    )
+    .await?;
+
+    run_migration(
+        db,
+        "12_id_bloom_filter_index",
+        SkipIndexMigration {
+            db_name,
+            name: "id_bloom_filter",
+            definition: "id TYPE bloom_filter GRANULARITY 4",
+        },
+    )
+    .await?;
+
     Ok(())
 }
 
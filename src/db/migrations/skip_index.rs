@@ -0,0 +1,60 @@
+use super::migratable::Migratable;
+use anyhow::{bail, Context};
+use std::{env, time::Instant};
+use tracing::info;
+
+/// Adds a skip index to `message_structured`, so queries filtering on its expression can skip granules.
+/// Existing data is indexed one partition at a time, as it can take a while on large databases.
+pub struct SkipIndexMigration<'a> {
+    pub db_name: &'a str,
+    pub name: &'a str,
+    /// Indexed expression, type and granularity
+    pub definition: &'a str,
+}
+
+impl<'a> Migratable<'a> for SkipIndexMigration<'a> {
+    async fn run(&self, db: &'a clickhouse::Client) -> anyhow::Result<()> {
+        let name = self.name;
+
+        let partitions = db
+            .query("SELECT DISTINCT partition_id FROM system.parts WHERE database = ? AND table = 'message_structured' AND active ORDER BY partition_id ASC")
+            .bind(self.db_name)
+            .fetch_all::<String>()
+            .await
+            .context("Could not fetch partition list")?;
+
+        if partitions.len() > 1
+            && env::var("RUSTLOG_ACKNOWLEDGE_INDEX_MIGRATION").as_deref() != Ok("1")
+        {
+            bail!(
+                "The current version of rustlog needs to build the index {name} for all existing messages. This process can take from a few minutes to several hours depending on the database size. \
+                Set the environment variable RUSTLOG_ACKNOWLEDGE_INDEX_MIGRATION=1 to confirm and run the migration, or downgrade to an older version if you don't want to run it right now."
+            );
+        }
+
+        db.query(&format!(
+            "ALTER TABLE message_structured ADD INDEX IF NOT EXISTS {name} {}",
+            self.definition
+        ))
+        .execute()
+        .await?;
+
+        info!("Building index {name} for {} partitions", partitions.len());
+        let started_at = Instant::now();
+
+        for partition in partitions {
+            info!("Building index {name} for partition {partition}");
+            db.query(&format!(
+                "ALTER TABLE message_structured MATERIALIZE INDEX {name} IN PARTITION ID ? SETTINGS mutations_sync = 1"
+            ))
+            .bind(&partition)
+            .execute()
+            .await
+            .with_context(|| format!("Could not build index {name} for partition {partition}"))?;
+        }
+
+        info!("Index {name} built in {:?}", started_at.elapsed());
+
+        Ok(())
+    }
+}
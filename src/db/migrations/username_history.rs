@@ -2,6 +2,17 @@ use super::migratable::Migratable;
 use anyhow::Context;
 use tracing::{info, warn};
 
+pub const CREATE_USERNAME_HISTORY_MV: &str = "
+CREATE MATERIALIZED VIEW username_history_mv
+TO username_history
+AS SELECT
+    user_id,
+    user_login,
+    minSimpleState(timestamp) AS first_timestamp,
+    maxSimpleState(timestamp) AS last_timestamp
+FROM message_structured
+GROUP BY user_id, user_login";
+
 pub struct UsernameHistoryMigration;
 
 impl<'a> Migratable<'a> for UsernameHistoryMigration {
@@ -54,21 +65,7 @@ impl<'a> Migratable<'a> for UsernameHistoryMigration {
             .context("Could not fill username history")?;
         }
 
-        db.query(
-            "
-            CREATE MATERIALIZED VIEW username_history_mv
-            TO username_history
-            AS SELECT
-                user_id,
-                user_login,
-                minSimpleState(timestamp) AS first_timestamp,
-                maxSimpleState(timestamp) AS last_timestamp
-            FROM message_structured
-            GROUP BY user_id, user_login
-        ",
-        )
-        .execute()
-        .await?;
+        db.query(CREATE_USERNAME_HISTORY_MV).execute().await?;
 
         if let Err(err) = db.query("OPTIMIZE TABLE username_history").execute().await {
             warn!("Could not run OPTIMIZE query on table: {err}");
@@ -1,28 +1,48 @@
+mod message_index;
 mod migrations;
 pub mod schema;
+pub mod search;
+pub mod spool;
 pub mod writer;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 
 pub use migrations::run as setup_db;
+use search::SearchQuery;
 use serde::Deserialize;
 use writer::FlushBuffer;
 
 use crate::{
     error::Error,
     logs::{
-        schema::LogRangeParams,
+        schema::{Deletions, LogGap, LogRangeParams, CLEARCHAT_WINDOW_MS},
         stream::{FlushBufferResponse, LogsStream},
     },
-    web::schema::{AvailableLogDate, LogsParams, PreviousName, UserLogsStats},
+    web::schema::{
+        AvailableLogDate, DeletionMutation, LeaderboardParams, LeaderboardRanking, LogsParams,
+        PreviousName, StatsBucket, UserLogsStats,
+    },
     Result,
 };
 use chrono::{DateTime, Datelike, Duration, Utc};
-use clickhouse::{query::RowCursor, Client, Row};
+use clickhouse::{
+    query::{Query, RowCursor},
+    Client, Row,
+};
 use rand::{rng, seq::IteratorRandom};
-use schema::StructuredMessage;
-use tracing::debug;
+use schema::{
+    ConnectionEvent, ConnectionEventType, MessageFlags, MessageKey, MessageType, OptOutEvent,
+    OptOutEventType, StructuredMessage, CONNECTION_EVENTS_TABLE, MESSAGES_STRUCTURED_TABLE,
+    OPT_OUT_EVENTS_TABLE,
+};
+use tmi::Tag;
+use tracing::{debug, info};
+use uuid::Uuid;
 
 const CHANNEL_MULTI_QUERY_SIZE_DAYS: i64 = 14;
+const MESSAGE_CONTEXT_WINDOW_HOURS: i64 = 24;
+/// User notice types which count as a sub in activity stats. Mystery gifts are left out,
+/// because every sub in them is announced as a separate gift as well
+const SUB_EVENT_MSG_IDS: &[&str] = &["sub", "resub", "subgift", "anonsubgift"];
 
 pub async fn read_channel(
     db: &Client,
@@ -30,13 +50,27 @@ pub async fn read_channel(
     params: LogsParams,
     flush_buffer: &FlushBuffer,
     (from, to): (DateTime<Utc>, DateTime<Utc>),
+    excluded_user_ids: &[String],
 ) -> Result<LogsStream> {
-    let buffer_response =
-        FlushBufferResponse::new(flush_buffer, channel_id, None, params, (from, to)).await;
+    let buffer_response = FlushBufferResponse::new(
+        flush_buffer,
+        channel_id,
+        None,
+        params,
+        (from, to),
+        excluded_user_ids,
+    )
+    .await;
 
     let suffix = if params.reverse { "DESC" } else { "ASC" };
+    let exclude_filter = if excluded_user_ids.is_empty() {
+        ""
+    } else {
+        " AND NOT has(?, user_id)"
+    };
 
-    let mut query = format!("SELECT ?fields FROM message_structured WHERE channel_id = ? AND timestamp >= ? AND timestamp < ? ORDER BY timestamp {suffix}");
+    // FINAL collapses duplicate messages which haven't been merged in the background yet
+    let mut query = format!("SELECT ?fields FROM message_structured FINAL WHERE channel_id = ? AND timestamp >= ? AND timestamp < ?{exclude_filter} ORDER BY timestamp {suffix}");
 
     if to - from > Duration::days(CHANNEL_MULTI_QUERY_SIZE_DAYS) {
         let count = db
@@ -57,14 +91,22 @@ pub async fn read_channel(
         let mut current_to = current_from + interval;
 
         loop {
-            let cursor = next_cursor(db, &query, channel_id, current_from, current_to)?;
+            let cursor = next_cursor(
+                db,
+                &query,
+                channel_id,
+                current_from,
+                current_to,
+                excluded_user_ids,
+            )?;
             streams.push(cursor);
 
             current_from += interval;
             current_to += interval;
 
             if current_to > to {
-                let cursor = next_cursor(db, &query, channel_id, current_from, to)?;
+                let cursor =
+                    next_cursor(db, &query, channel_id, current_from, to, excluded_user_ids)?;
                 streams.push(cursor);
                 break;
             }
@@ -80,12 +122,7 @@ pub async fn read_channel(
     } else {
         apply_limit_offset(&mut query, &buffer_response);
 
-        let cursor = db
-            .query(&query)
-            .bind(channel_id)
-            .bind(from.timestamp_millis() as f64 / 1000.0)
-            .bind(to.timestamp_millis() as f64 / 1000.0)
-            .fetch()?;
+        let cursor = next_cursor(db, &query, channel_id, from, to, excluded_user_ids)?;
         LogsStream::new_cursor(cursor, buffer_response).await
     }
 }
@@ -96,13 +133,17 @@ fn next_cursor(
     channel_id: &str,
     from: DateTime<Utc>,
     to: DateTime<Utc>,
+    excluded_user_ids: &[String],
 ) -> Result<RowCursor<StructuredMessage<'static>>> {
-    let cursor = db
+    let mut query = db
         .query(query)
         .bind(channel_id)
         .bind(from.timestamp_millis() as f64 / 1000.0)
-        .bind(to.timestamp_millis() as f64 / 1000.0)
-        .fetch()?;
+        .bind(to.timestamp_millis() as f64 / 1000.0);
+    if !excluded_user_ids.is_empty() {
+        query = query.bind(excluded_user_ids);
+    }
+    let cursor = query.fetch()?;
     Ok(cursor)
 }
 
@@ -114,11 +155,18 @@ pub async fn read_user(
     flush_buffer: &FlushBuffer,
     (from, to): (DateTime<Utc>, DateTime<Utc>),
 ) -> Result<LogsStream> {
-    let buffer_response =
-        FlushBufferResponse::new(flush_buffer, channel_id, Some(user_id), params, (from, to)).await;
+    let buffer_response = FlushBufferResponse::new(
+        flush_buffer,
+        channel_id,
+        Some(user_id),
+        params,
+        (from, to),
+        &[],
+    )
+    .await;
 
     let suffix = if params.reverse { "DESC" } else { "ASC" };
-    let mut query = format!("SELECT * FROM message_structured WHERE channel_id = ? AND user_id = ? AND timestamp >= ? AND timestamp < ? ORDER BY timestamp {suffix}");
+    let mut query = format!("SELECT * FROM message_structured FINAL WHERE channel_id = ? AND user_id = ? AND timestamp >= ? AND timestamp < ? ORDER BY timestamp {suffix}");
     apply_limit_offset(&mut query, &buffer_response);
 
     let cursor = db
@@ -131,6 +179,56 @@ pub async fn read_user(
     LogsStream::new_cursor(cursor, buffer_response).await
 }
 
+/// Messages of a user in all of the given channels, merged by timestamp
+pub async fn read_user_in_channels(
+    db: &Client,
+    channel_ids: &[String],
+    user_id: &str,
+    params: LogsParams,
+    flush_buffer: &FlushBuffer,
+    (from, to): (DateTime<Utc>, DateTime<Utc>),
+) -> Result<LogsStream> {
+    let buffer_response =
+        FlushBufferResponse::for_user(flush_buffer, channel_ids, user_id, params, (from, to)).await;
+
+    let suffix = if params.reverse { "DESC" } else { "ASC" };
+    let mut query = format!("SELECT * FROM message_structured FINAL WHERE has(?, channel_id) AND user_id = ? AND timestamp >= ? AND timestamp < ? ORDER BY timestamp {suffix}");
+    apply_limit_offset(&mut query, &buffer_response);
+
+    let cursor = db
+        .query(&query)
+        .bind(channel_ids)
+        .bind(user_id)
+        .bind(from.timestamp_millis() as f64 / 1000.0)
+        .bind(to.timestamp_millis() as f64 / 1000.0)
+        .fetch()?;
+    LogsStream::new_cursor(cursor, buffer_response).await
+}
+
+#[derive(Deserialize, Row)]
+pub struct UserChannelRow {
+    pub channel_id: String,
+    pub message_count: u64,
+    pub first_timestamp: i64,
+    pub last_timestamp: i64,
+}
+
+/// All channels a user has sent messages in, most recently active first
+pub async fn read_user_channels(db: &Client, user_id: &str) -> Result<Vec<UserChannelRow>> {
+    let rows = db
+        .query(
+            "SELECT channel_id, count() AS message_count, min(timestamp) AS first_timestamp, max(timestamp) AS last_timestamp
+            FROM message_structured FINAL
+            WHERE user_id = ?
+            GROUP BY channel_id
+            ORDER BY last_timestamp DESC",
+        )
+        .bind(user_id)
+        .fetch_all()
+        .await?;
+    Ok(rows)
+}
+
 pub async fn read_available_channel_logs(
     db: &Client,
     channel_id: &str,
@@ -225,6 +323,86 @@ pub async fn read_random_user_line(
     Ok(msg)
 }
 
+pub async fn read_message(
+    db: &Client,
+    flush_buffer: &FlushBuffer,
+    id: Uuid,
+) -> Result<StructuredMessage<'static>> {
+    if let Some(message) = flush_buffer.message_by_id(id).await {
+        return Ok(message);
+    }
+
+    db.query("SELECT ?fields FROM message_structured FINAL WHERE id = ? LIMIT 1")
+        .bind(id.to_string())
+        .fetch_optional()
+        .await?
+        .ok_or(Error::NotFound)
+}
+
+/// Up to `count` messages of the channel before and after the given message, ordered by timestamp.
+/// Only messages within `MESSAGE_CONTEXT_WINDOW_HOURS` are included, so the query doesn't scan the entire channel.
+pub async fn read_message_context(
+    db: &Client,
+    flush_buffer: &FlushBuffer,
+    message: &StructuredMessage<'_>,
+    count: u64,
+) -> Result<(
+    Vec<StructuredMessage<'static>>,
+    Vec<StructuredMessage<'static>>,
+)> {
+    let window = Duration::hours(MESSAGE_CONTEXT_WINDOW_HOURS).num_milliseconds() as u64;
+    let timestamp = message.timestamp;
+    let range_start = timestamp.saturating_sub(window);
+    let range_end = timestamp + window;
+
+    // Messages sent in the same millisecond are ordered by id, which compares the same way as its string in ClickHouse
+    let key = |msg: &StructuredMessage| (msg.timestamp, msg.uuid().unwrap_or_default());
+    let message_key = key(message);
+    let id = message_key.1.to_string();
+
+    let mut before: Vec<StructuredMessage<'static>> = db
+        .query("SELECT ?fields FROM message_structured FINAL WHERE channel_id = ? AND timestamp >= ? AND timestamp <= ? AND (timestamp < ? OR toString(id) < ?) ORDER BY timestamp DESC, toString(id) DESC LIMIT ?")
+        .bind(message.channel_id.as_ref())
+        .bind(range_start as f64 / 1000.0)
+        .bind(timestamp as f64 / 1000.0)
+        .bind(timestamp as f64 / 1000.0)
+        .bind(&id)
+        .bind(count)
+        .fetch_all()
+        .await?;
+    before.extend(
+        flush_buffer
+            .messages_by_channel(range_start..timestamp + 1, &message.channel_id)
+            .await
+            .into_iter()
+            .filter(|msg| key(msg) < message_key),
+    );
+    before.sort_by_key(key);
+    let before = before.split_off(before.len().saturating_sub(count as usize));
+
+    let mut after: Vec<StructuredMessage<'static>> = db
+        .query("SELECT ?fields FROM message_structured FINAL WHERE channel_id = ? AND timestamp >= ? AND timestamp <= ? AND (timestamp > ? OR toString(id) > ?) ORDER BY timestamp ASC, toString(id) ASC LIMIT ?")
+        .bind(message.channel_id.as_ref())
+        .bind(timestamp as f64 / 1000.0)
+        .bind(range_end as f64 / 1000.0)
+        .bind(timestamp as f64 / 1000.0)
+        .bind(&id)
+        .bind(count)
+        .fetch_all()
+        .await?;
+    after.extend(
+        flush_buffer
+            .messages_by_channel(timestamp..range_end + 1, &message.channel_id)
+            .await
+            .into_iter()
+            .filter(|msg| key(msg) > message_key),
+    );
+    after.sort_by_key(key);
+    after.truncate(count as usize);
+
+    Ok((before, after))
+}
+
 pub async fn read_random_channel_line(
     db: &Client,
     channel_id: &str,
@@ -261,49 +439,224 @@ pub async fn read_random_channel_line(
     Ok(msg)
 }
 
-pub async fn delete_user_logs(_db: &Client, _user_id: &str) -> Result<()> {
-    // info!("Deleting all logs for user {user_id}");
-    // db.query("ALTER TABLE message DELETE WHERE user_id = ?")
-    //     .bind(user_id)
-    //     .execute()
-    //     .await?;
+/// Tables that contain user data and need to be cleared when a user opts out
+const USER_DATA_TABLES: &[&str] = &[MESSAGES_STRUCTURED_TABLE, "username_history"];
+
+/// Deletes all logs of a user, including deletions of their messages, which are matched by `user_logins`
+pub async fn delete_user_logs(db: &Client, user_id: &str, user_logins: &[String]) -> Result<()> {
+    info!("Deleting all logs for user {user_id}");
+
+    // Lightweight deletes are not supported on tables with projections, so mutations are used instead.
+    // They run asynchronously in the background, progress can be checked with `get_user_deletion_status`
+    for table in USER_DATA_TABLES {
+        let delete_clear_msgs = *table == MESSAGES_STRUCTURED_TABLE && !user_logins.is_empty();
+
+        let mut query = format!("ALTER TABLE {table} DELETE WHERE user_id = ?");
+        if delete_clear_msgs {
+            query.push_str(&format!(
+                " OR (message_type = {} AND has(?, trim(LEADING ':' FROM user_login)))",
+                MessageType::ClearMsg as u8
+            ));
+        }
+
+        let mut query = db.query(&query).bind(user_id);
+        if delete_clear_msgs {
+            query = query.bind(user_logins);
+        }
+        query.execute().await?;
+    }
+
+    Ok(())
+}
+
+pub async fn write_opt_out_event(
+    db: &Client,
+    user_id: &str,
+    event_type: OptOutEventType,
+) -> Result<()> {
+    let mut insert = db.insert(OPT_OUT_EVENTS_TABLE)?;
+    insert
+        .write(&OptOutEvent {
+            user_id: user_id.to_owned(),
+            timestamp: Utc::now().timestamp_millis() as u64,
+            event_type,
+        })
+        .await?;
+    insert.end().await?;
     Ok(())
 }
 
+pub async fn read_opt_out_events(db: &Client, user_id: &str) -> Result<Vec<OptOutEvent>> {
+    let events = db
+        .query("SELECT ?fields FROM opt_out_event WHERE user_id = ? ORDER BY timestamp ASC")
+        .bind(user_id)
+        .fetch_all()
+        .await?;
+    Ok(events)
+}
+
+/// Returns when the user last opted back in, if they ever did
+pub async fn read_last_opt_in(db: &Client, user_id: &str) -> Result<Option<DateTime<Utc>>> {
+    let event = db
+        .query(
+            "SELECT ?fields FROM opt_out_event WHERE user_id = ? AND event_type = ?
+            ORDER BY timestamp DESC LIMIT 1",
+        )
+        .bind(user_id)
+        .bind(OptOutEventType::OptIn as u8)
+        .fetch_optional::<OptOutEvent>()
+        .await?;
+    Ok(event.map(|event| {
+        DateTime::from_timestamp_millis(event.timestamp as i64).expect("Invalid DateTime")
+    }))
+}
+
+pub async fn get_user_deletion_status(db: &Client, user_id: &str) -> Result<Vec<DeletionMutation>> {
+    #[derive(Deserialize, Row)]
+    struct MutationRow {
+        table: String,
+        mutation_id: String,
+        create_time: u32,
+        is_done: u8,
+        parts_to_do: u64,
+        latest_fail_reason: String,
+    }
+
+    // Mutation commands are stored in a normalized form, e.g. `DELETE WHERE user_id = '123'`
+    let command_filter = format!(
+        "user_id = '{}'",
+        user_id.replace('\\', "\\\\").replace('\'', "\\'")
+    );
+
+    let rows: Vec<MutationRow> = db
+        .query(
+            "SELECT table, mutation_id, toUnixTimestamp(create_time) AS create_time, is_done, toUInt64(parts_to_do) AS parts_to_do, latest_fail_reason
+            FROM system.mutations
+            WHERE database = currentDatabase() AND has(?, table) AND startsWith(command, 'DELETE') AND position(command, ?) != 0
+            ORDER BY create_time ASC",
+        )
+        .bind(USER_DATA_TABLES)
+        .bind(command_filter)
+        .fetch_all()
+        .await?;
+
+    let mutations = rows
+        .into_iter()
+        .map(|row| DeletionMutation {
+            table: row.table,
+            mutation_id: row.mutation_id,
+            created_at: DateTime::from_timestamp(row.create_time.into(), 0)
+                .expect("Invalid DateTime"),
+            is_done: row.is_done == 1,
+            parts_to_do: row.parts_to_do,
+            latest_fail_reason: Some(row.latest_fail_reason).filter(|reason| !reason.is_empty()),
+        })
+        .collect();
+
+    Ok(mutations)
+}
+
 pub async fn search_user_logs(
     db: &Client,
     channel_id: &str,
     user_id: &str,
-    search: &str,
+    search: &SearchQuery,
     params: LogsParams,
 ) -> Result<LogsStream> {
     let buffer_response = FlushBufferResponse::empty(params);
+    let search = search.compile();
 
     let suffix = if params.reverse { "DESC" } else { "ASC" };
 
-    let mut query = format!("SELECT * FROM message_structured WHERE channel_id = ? AND user_id = ? AND positionCaseInsensitive(text, ?) != 0 ORDER BY timestamp {suffix}");
+    let mut query = format!("SELECT * FROM message_structured FINAL WHERE channel_id = ? AND user_id = ? AND {} ORDER BY timestamp {suffix}", search.filter);
     apply_limit_offset(&mut query, &buffer_response);
 
-    let cursor = db
-        .query(&query)
-        .bind(channel_id)
-        .bind(user_id)
-        .bind(search)
-        .fetch()?;
+    let query = db.query(&query).bind(channel_id).bind(user_id);
+    let cursor = search.bind(query).fetch()?;
 
     LogsStream::new_cursor(cursor, buffer_response).await
 }
 
+/// Returns the matching messages and how many there are in total, regardless of the limit
+pub async fn search_channel_logs(
+    db: &Client,
+    flush_buffer: &FlushBuffer,
+    channel_id: &str,
+    search: &SearchQuery,
+    range_params: LogRangeParams,
+    params: LogsParams,
+    excluded_user_ids: &[String],
+) -> Result<(LogsStream, u64)> {
+    let (from, to) = range_params
+        .range()
+        .unwrap_or((DateTime::UNIX_EPOCH, DateTime::<Utc>::MAX_UTC));
+    let matcher = search.matcher();
+    let buffered: Vec<_> = flush_buffer
+        .messages_by_channel(
+            (from.timestamp_millis() as u64)..(to.timestamp_millis() as u64),
+            channel_id,
+        )
+        .await
+        .into_iter()
+        .filter(|msg| !excluded_user_ids.iter().any(|id| *id == msg.user_id))
+        .filter(|msg| matcher.matches(msg))
+        .collect();
+    let buffered_count = buffered.len() as u64;
+    let buffer_response = FlushBufferResponse::from_messages(buffered, params);
+    let search = search.compile();
+
+    let mut filter = format!("channel_id = ? AND {}", search.filter);
+    if range_params.range().is_some() {
+        filter.push_str(" AND timestamp >= ? AND timestamp < ?");
+    }
+    if !excluded_user_ids.is_empty() {
+        filter.push_str(" AND NOT has(?, user_id)");
+    }
+
+    let bind_filter = |mut query: Query| {
+        query = search.bind(query.bind(channel_id));
+        if let Some((from, to)) = range_params.range() {
+            query = query
+                .bind(from.timestamp_millis() as f64 / 1000.0)
+                .bind(to.timestamp_millis() as f64 / 1000.0);
+        }
+        if !excluded_user_ids.is_empty() {
+            query = query.bind(excluded_user_ids);
+        }
+        query
+    };
+
+    let total_count = bind_filter(db.query(&format!(
+        "SELECT count() FROM message_structured FINAL WHERE {filter}"
+    )))
+    .fetch_one::<u64>()
+    .await?
+        + buffered_count;
+
+    let suffix = if params.reverse { "DESC" } else { "ASC" };
+    let mut query = format!(
+        "SELECT * FROM message_structured FINAL WHERE {filter} ORDER BY timestamp {suffix}"
+    );
+    apply_limit_offset(&mut query, &buffer_response);
+
+    let cursor = bind_filter(db.query(&query)).fetch()?;
+    let stream = LogsStream::new_cursor(cursor, buffer_response).await?;
+
+    Ok((stream, total_count))
+}
+
 #[derive(Deserialize, Row)]
 pub struct StatsRow {
     pub cnt: u64,
     pub user_id: String,
 }
 
+/// Ignored users are not included in the top chatters, but are still counted in the total
 pub async fn get_channel_stats(
     db: &Client,
     channel_id: &str,
     range_params: LogRangeParams,
+    ignored_user_ids: &[String],
 ) -> Result<(u64, Vec<StatsRow>)> {
     let mut query = "SELECT count(*) FROM message_structured WHERE channel_id = ?".to_owned();
 
@@ -328,6 +681,10 @@ pub async fn get_channel_stats(
         query.push_str(" AND timestamp >= ? AND timestamp < ?");
     }
 
+    if !ignored_user_ids.is_empty() {
+        query.push_str(" AND NOT has(?, user_id)");
+    }
+
     query.push_str(" GROUP BY user_id ORDER BY cnt DESC LIMIT 5 SETTINGS use_query_cache = 1, query_cache_ttl = 300");
 
     let mut query = db.query(&query).bind(channel_id);
@@ -338,11 +695,247 @@ pub async fn get_channel_stats(
             .bind(to.timestamp_millis() as f64 / 1000.0);
     }
 
+    if !ignored_user_ids.is_empty() {
+        query = query.bind(ignored_user_ids);
+    }
+
     let stats_rows = query.fetch_all::<StatsRow>().await?;
 
     Ok((total_count, stats_rows))
 }
 
+#[derive(Deserialize, Row)]
+pub struct LeaderboardRow {
+    pub user_id: String,
+    pub message_count: u64,
+    pub active_days: u64,
+}
+
+/// Returns the total amount of ranked users along with the requested page
+pub async fn get_channel_leaderboard(
+    db: &Client,
+    channel_id: &str,
+    range_params: LogRangeParams,
+    params: &LeaderboardParams,
+    message_type: MessageType,
+    limit: u64,
+    ignored_user_ids: &[String],
+) -> Result<(u64, Vec<LeaderboardRow>)> {
+    let mut filter = format!(
+        "channel_id = ? AND user_id != '' AND message_type = {}",
+        message_type as u8
+    );
+    if range_params.range().is_some() {
+        filter.push_str(" AND timestamp >= ? AND timestamp < ?");
+    }
+    if let Some(badge) = params.badge {
+        filter.push_str(&format!(
+            " AND bitAnd(message_flags, {}) != 0",
+            badge.flag().bits()
+        ));
+    }
+    if !ignored_user_ids.is_empty() {
+        filter.push_str(" AND NOT has(?, user_id)");
+    }
+
+    let bind_filter = |mut query: Query| {
+        query = query.bind(channel_id);
+        if let Some((from, to)) = range_params.range() {
+            query = query
+                .bind(from.timestamp_millis() as f64 / 1000.0)
+                .bind(to.timestamp_millis() as f64 / 1000.0);
+        }
+        if !ignored_user_ids.is_empty() {
+            query = query.bind(ignored_user_ids);
+        }
+        query
+    };
+
+    let total_count = bind_filter(db.query(&format!(
+        "SELECT uniqExact(user_id) FROM message_structured WHERE {filter} SETTINGS use_query_cache = 1, query_cache_ttl = 300"
+    )))
+    .fetch_one()
+    .await?;
+
+    let order = match params.rank_by {
+        LeaderboardRanking::Messages => "message_count DESC, active_days DESC",
+        LeaderboardRanking::ActiveDays => "active_days DESC, message_count DESC",
+    };
+    let query = format!(
+        "SELECT user_id, count() AS message_count, uniqExact(toDate(timestamp, 'UTC')) AS active_days
+        FROM message_structured FINAL
+        WHERE {filter}
+        GROUP BY user_id
+        ORDER BY {order}, user_id ASC
+        LIMIT {limit} OFFSET {offset}
+        SETTINGS use_query_cache = 1, query_cache_ttl = 300",
+        offset = params.offset.unwrap_or(0),
+    );
+    let rows = bind_filter(db.query(&query)).fetch_all().await?;
+
+    Ok((total_count, rows))
+}
+
+#[derive(Default)]
+pub struct ActivityRow {
+    pub bucket: u32,
+    pub message_count: u64,
+    pub unique_chatters: u64,
+    pub first_time_chatters: u64,
+    pub sub_events: u64,
+    pub moderation_actions: u64,
+}
+
+/// Channel activity grouped into buckets of the given size, oldest first
+pub async fn get_channel_activity(
+    db: &Client,
+    flush_buffer: &FlushBuffer,
+    channel_id: &str,
+    bucket: StatsBucket,
+    (from, to): (DateTime<Utc>, DateTime<Utc>),
+) -> Result<Vec<ActivityRow>> {
+    #[derive(Deserialize, Row)]
+    struct QueryRow {
+        bucket: u32,
+        message_count: u64,
+        unique_chatters: u64,
+        first_time_chatters: u64,
+        sub_events: u64,
+        moderation_actions: u64,
+        buffered_bucket_chatters: Vec<String>,
+    }
+
+    let buffered = flush_buffer
+        .messages_by_channel(
+            from.timestamp_millis() as u64..to.timestamp_millis() as u64,
+            channel_id,
+        )
+        .await;
+    // Chatters of buckets which have buffered messages are needed to count unique chatters across both
+    let buffered_from = buffered
+        .iter()
+        .map(|msg| bucket.start(msg.timestamp))
+        .min()
+        .unwrap_or(u32::MAX);
+
+    let bucket_expression = match bucket {
+        StatsBucket::Minute => "toStartOfMinute(timestamp, 'UTC')",
+        StatsBucket::Hour => "toStartOfHour(timestamp, 'UTC')",
+        StatsBucket::Day => "toStartOfDay(timestamp, 'UTC')",
+        StatsBucket::Week => "toMonday(timestamp, 'UTC')",
+    };
+    // Results for ranges which are over won't change anymore
+    let settings = if to < Utc::now() {
+        " SETTINGS use_query_cache = 1, query_cache_ttl = 300"
+    } else {
+        ""
+    };
+
+    let query = format!(
+        "SELECT toUInt32(toDateTime({bucket_expression}, 'UTC')) AS bucket,
+        countIf(message_type = {privmsg}) AS message_count,
+        uniqExactIf(user_id, message_type = {privmsg}) AS unique_chatters,
+        countIf(message_type = {privmsg} AND bitAnd(message_flags, {first_msg}) != 0) AS first_time_chatters,
+        countIf(message_type = {user_notice} AND has(?, extra_tags['msg-id'])) AS sub_events,
+        countIf((message_type = {clear_chat} AND user_id != '') OR message_type = {clear_msg}) AS moderation_actions,
+        groupUniqArrayIf(user_id, message_type = {privmsg} AND bucket >= ?) AS buffered_bucket_chatters
+        FROM message_structured FINAL
+        WHERE channel_id = ? AND timestamp >= ? AND timestamp < ?
+        GROUP BY bucket
+        ORDER BY bucket ASC{settings}",
+        privmsg = MessageType::PrivMsg as u8,
+        user_notice = MessageType::UserNotice as u8,
+        clear_chat = MessageType::ClearChat as u8,
+        clear_msg = MessageType::ClearMsg as u8,
+        first_msg = MessageFlags::FIRST_MSG.bits(),
+    );
+
+    let rows: Vec<QueryRow> = db
+        .query(&query)
+        .bind(SUB_EVENT_MSG_IDS)
+        .bind(buffered_from)
+        .bind(channel_id)
+        .bind(from.timestamp_millis() as f64 / 1000.0)
+        .bind(to.timestamp_millis() as f64 / 1000.0)
+        .fetch_all()
+        .await?;
+
+    let mut chatters: BTreeMap<u32, HashSet<String>> = BTreeMap::new();
+    let mut buckets: BTreeMap<u32, ActivityRow> = BTreeMap::new();
+    for row in rows {
+        if !row.buffered_bucket_chatters.is_empty() {
+            chatters.insert(
+                row.bucket,
+                row.buffered_bucket_chatters.into_iter().collect(),
+            );
+        }
+        buckets.insert(
+            row.bucket,
+            ActivityRow {
+                bucket: row.bucket,
+                message_count: row.message_count,
+                unique_chatters: row.unique_chatters,
+                first_time_chatters: row.first_time_chatters,
+                sub_events: row.sub_events,
+                moderation_actions: row.moderation_actions,
+            },
+        );
+    }
+
+    for msg in buffered {
+        let start = bucket.start(msg.timestamp);
+        let row = buckets.entry(start).or_insert_with(|| ActivityRow {
+            bucket: start,
+            ..Default::default()
+        });
+
+        match msg.message_type {
+            MessageType::PrivMsg => {
+                row.message_count += 1;
+                if chatters
+                    .entry(start)
+                    .or_default()
+                    .insert(msg.user_id.to_string())
+                {
+                    row.unique_chatters += 1;
+                }
+                if msg.message_flags.contains(MessageFlags::FIRST_MSG) {
+                    row.first_time_chatters += 1;
+                }
+            }
+            MessageType::UserNotice
+                if msg
+                    .extra_tag(Tag::MsgId)
+                    .is_some_and(|msg_id| SUB_EVENT_MSG_IDS.contains(&msg_id)) =>
+            {
+                row.sub_events += 1;
+            }
+            MessageType::ClearChat if !msg.user_id.is_empty() => row.moderation_actions += 1,
+            MessageType::ClearMsg => row.moderation_actions += 1,
+            _ => (),
+        }
+    }
+
+    // Periods without activity between active buckets are included with zero counts
+    let step = bucket.seconds() as u32;
+    let mut filled: Vec<ActivityRow> = Vec::with_capacity(buckets.len());
+    for (start, row) in buckets {
+        if let Some(previous) = filled.last().map(|row| row.bucket) {
+            filled.extend(
+                (previous + step..start)
+                    .step_by(step as usize)
+                    .map(|bucket| ActivityRow {
+                        bucket,
+                        ..Default::default()
+                    }),
+            );
+        }
+        filled.push(row);
+    }
+
+    Ok(filled)
+}
+
 pub async fn get_user_stats(
     db: &Client,
     channel_id: &str,
@@ -374,6 +967,195 @@ pub async fn get_user_stats(
     })
 }
 
+#[derive(Deserialize, Row)]
+pub struct ModerationActionRow {
+    pub timestamp: i64,
+    pub message_type: MessageType,
+    pub user_id: String,
+    pub user_login: String,
+    pub ban_duration: String,
+    pub target_msg_id: String,
+    pub text: String,
+}
+
+/// Bans, timeouts and message deletions in a channel, newest first.
+///
+/// Deleted messages only carry the login of their sender, so matching a user's deletions relies on their logins
+pub async fn read_moderation_actions(
+    db: &Client,
+    channel_id: &str,
+    user: Option<(&str, &[String])>,
+    range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+) -> Result<Vec<ModerationActionRow>> {
+    // Clears of the whole chat have no target user
+    let mut query = format!(
+        "SELECT timestamp, message_type, user_id, trim(LEADING ':' FROM user_login) AS user_login,
+        extra_tags['ban-duration'] AS ban_duration, extra_tags['target-msg-id'] AS target_msg_id,
+        trim(LEADING ':' FROM text) AS text
+        FROM message_structured FINAL
+        WHERE channel_id = ? AND ((message_type = {clear_chat} AND user_id != '') OR message_type = {clear_msg})",
+        clear_chat = MessageType::ClearChat as u8,
+        clear_msg = MessageType::ClearMsg as u8,
+    );
+
+    if user.is_some() {
+        query.push_str(&format!(
+            " AND (user_id = ? OR (message_type = {} AND has(?, trim(LEADING ':' FROM user_login))))",
+            MessageType::ClearMsg as u8
+        ));
+    }
+
+    if range.is_some() {
+        query.push_str(" AND timestamp >= ? AND timestamp < ?");
+    }
+
+    query.push_str(" ORDER BY timestamp DESC");
+
+    let mut query = db.query(&query).bind(channel_id);
+
+    if let Some((user_id, user_logins)) = user {
+        query = query.bind(user_id).bind(user_logins);
+    }
+
+    if let Some((from, to)) = range {
+        query = query
+            .bind(from.timestamp_millis() as f64 / 1000.0)
+            .bind(to.timestamp_millis() as f64 / 1000.0);
+    }
+
+    let rows = query.fetch_all().await?;
+    Ok(rows)
+}
+
+/// Deletions and bans/timeouts which affect messages in the given range.
+/// If a user is specified, only bans and timeouts of that user are included.
+pub async fn read_deletions(
+    db: &Client,
+    flush_buffer: &FlushBuffer,
+    channel_ids: &[String],
+    user_id: Option<&str>,
+    (from, to): (DateTime<Utc>, DateTime<Utc>),
+) -> Result<Deletions> {
+    #[derive(Deserialize, Row)]
+    struct DeletionRow {
+        channel_id: String,
+        timestamp: u64,
+        message_type: MessageType,
+        user_id: String,
+        target_msg_id: String,
+    }
+
+    let from = from.timestamp_millis() as u64;
+    let to = to.timestamp_millis() as u64 + CLEARCHAT_WINDOW_MS;
+
+    // Deletions don't have a user id, so together with clears of a single user they are a key range
+    let user_filter = if user_id.is_some() {
+        " AND (user_id = '' OR user_id = ?)"
+    } else {
+        ""
+    };
+    let query = format!(
+        "SELECT channel_id, timestamp, message_type, user_id, extra_tags['target-msg-id'] AS target_msg_id
+        FROM message_structured
+        WHERE has(?, channel_id) AND timestamp >= ? AND timestamp < ?{user_filter}
+        AND ((message_type = {clear_chat} AND user_id != '') OR message_type = {clear_msg})",
+        clear_chat = MessageType::ClearChat as u8,
+        clear_msg = MessageType::ClearMsg as u8,
+    );
+
+    let mut query = db
+        .query(&query)
+        .bind(channel_ids)
+        .bind(from as f64 / 1000.0)
+        .bind(to as f64 / 1000.0);
+    if let Some(user_id) = user_id {
+        query = query.bind(user_id);
+    }
+    let mut rows: Vec<DeletionRow> = query.fetch_all().await?;
+
+    for channel_id in channel_ids {
+        let buffered = flush_buffer.messages_by_channel(from..to, channel_id).await;
+        for msg in buffered.into_iter().filter(|msg| {
+            matches!(
+                msg.message_type,
+                MessageType::ClearChat | MessageType::ClearMsg
+            )
+        }) {
+            let target_msg_id = msg
+                .extra_tag(Tag::TargetMsgId)
+                .unwrap_or_default()
+                .to_owned();
+            rows.push(DeletionRow {
+                channel_id: msg.channel_id.into_owned(),
+                timestamp: msg.timestamp,
+                message_type: msg.message_type,
+                user_id: msg.user_id.into_owned(),
+                target_msg_id,
+            });
+        }
+    }
+
+    let mut deletions = Deletions::default();
+    for row in rows {
+        match row.message_type {
+            MessageType::ClearMsg => {
+                if let Ok(id) = Uuid::parse_str(&row.target_msg_id) {
+                    deletions.message_ids.insert(id);
+                }
+            }
+            MessageType::ClearChat
+                if !row.user_id.is_empty() && user_id.is_none_or(|id| id == row.user_id) =>
+            {
+                deletions
+                    .clears
+                    .entry(row.channel_id)
+                    .or_default()
+                    .entry(row.user_id)
+                    .or_default()
+                    .push(row.timestamp);
+            }
+            _ => (),
+        }
+    }
+
+    Ok(deletions)
+}
+
+/// User notices (subs, gifts, raids and so on) of a channel in the range, oldest first
+pub async fn read_channel_user_notices(
+    db: &Client,
+    flush_buffer: &FlushBuffer,
+    channel_id: &str,
+    (from, to): (DateTime<Utc>, DateTime<Utc>),
+) -> Result<Vec<StructuredMessage<'static>>> {
+    let query = format!(
+        "SELECT ?fields FROM message_structured FINAL WHERE channel_id = ? AND message_type = {} AND timestamp >= ? AND timestamp < ? ORDER BY timestamp ASC",
+        MessageType::UserNotice as u8
+    );
+    let mut messages = db
+        .query(&query)
+        .bind(channel_id)
+        .bind(from.timestamp_millis() as f64 / 1000.0)
+        .bind(to.timestamp_millis() as f64 / 1000.0)
+        .fetch_all::<StructuredMessage<'static>>()
+        .await?;
+
+    let buffered = flush_buffer
+        .messages_by_channel(
+            from.timestamp_millis() as u64..to.timestamp_millis() as u64,
+            channel_id,
+        )
+        .await;
+    messages.extend(
+        buffered
+            .into_iter()
+            .filter(|msg| msg.message_type == MessageType::UserNotice),
+    );
+    messages.sort_by_key(|msg| msg.timestamp);
+
+    Ok(messages)
+}
+
 pub async fn get_user_name_history(db: &Client, user_id: &str) -> Result<Vec<PreviousName>> {
     #[derive(Deserialize, Row)]
     struct SingleNameHistory {
@@ -415,6 +1197,125 @@ pub async fn get_user_name_history(db: &Client, user_id: &str) -> Result<Vec<Pre
     Ok(names)
 }
 
+pub async fn write_connection_events(db: &Client, events: &[ConnectionEvent]) -> Result<()> {
+    let mut insert = db.insert(CONNECTION_EVENTS_TABLE)?;
+    for event in events {
+        insert.write(event).await?;
+    }
+    insert.end().await?;
+    Ok(())
+}
+
+pub async fn read_last_connection_event(
+    db: &Client,
+    channel_id: &str,
+) -> Result<Option<ConnectionEvent>> {
+    let event = db
+        .query("SELECT ?fields FROM connection_event WHERE channel_id = ? ORDER BY timestamp DESC LIMIT 1")
+        .bind(channel_id)
+        .fetch_optional()
+        .await?;
+    Ok(event)
+}
+
+pub async fn read_last_message_timestamp(
+    db: &Client,
+    channel_id: &str,
+    since: u64,
+) -> Result<Option<u64>> {
+    let timestamp = db
+        .query(
+            "SELECT max(timestamp) FROM message_structured WHERE channel_id = ? AND timestamp >= ?",
+        )
+        .bind(channel_id)
+        .bind(since as f64 / 1000.0)
+        .fetch_one::<u64>()
+        .await?;
+    Ok(Some(timestamp).filter(|timestamp| *timestamp != 0))
+}
+
+/// Reads the keys of all messages in a channel in the given inclusive millisecond range
+pub async fn read_message_keys(
+    db: &Client,
+    channel_id: &str,
+    from: u64,
+    to: u64,
+) -> Result<Vec<MessageKey>> {
+    let keys = db
+        .query("SELECT ?fields FROM message_structured WHERE channel_id = ? AND timestamp >= ? AND timestamp <= ?")
+        .bind(channel_id)
+        .bind(from as f64 / 1000.0)
+        .bind(to as f64 / 1000.0)
+        .fetch_all()
+        .await?;
+    Ok(keys)
+}
+
+pub async fn read_log_gaps(
+    db: &Client,
+    channel_id: &str,
+    range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+) -> Result<Vec<LogGap>> {
+    // The last event before the range is needed to know if the range starts in a gap
+    let query = if range.is_some() {
+        "SELECT ?fields FROM connection_event WHERE channel_id = ? AND timestamp >= (
+            SELECT max(timestamp) FROM connection_event WHERE channel_id = ? AND timestamp < ?
+        ) AND timestamp < ? ORDER BY timestamp ASC"
+    } else {
+        "SELECT ?fields FROM connection_event WHERE channel_id = ? ORDER BY timestamp ASC"
+    };
+
+    let mut query = db.query(query).bind(channel_id);
+    if let Some((from, to)) = range {
+        query = query
+            .bind(channel_id)
+            .bind(from.timestamp_millis() as f64 / 1000.0)
+            .bind(to.timestamp_millis() as f64 / 1000.0);
+    }
+
+    let events = query.fetch_all::<ConnectionEvent>().await?;
+
+    let mut gaps = Vec::new();
+    let mut gap_start = None;
+
+    for event in events {
+        let timestamp =
+            DateTime::from_timestamp_millis(event.timestamp as i64).expect("Invalid DateTime");
+
+        match event.event_type {
+            ConnectionEventType::Disconnect => {
+                gap_start.get_or_insert((timestamp, false));
+            }
+            ConnectionEventType::UncertainDisconnect => {
+                gap_start.get_or_insert((timestamp, true));
+            }
+            ConnectionEventType::Connect | ConnectionEventType::Reconnect => {
+                if let Some((from, uncertain_start)) = gap_start.take() {
+                    gaps.push(LogGap {
+                        from,
+                        to: Some(timestamp),
+                        uncertain_start,
+                    });
+                }
+            }
+        }
+    }
+
+    if let Some((from, uncertain_start)) = gap_start {
+        gaps.push(LogGap {
+            from,
+            to: None,
+            uncertain_start,
+        });
+    }
+
+    if let Some((from, _)) = range {
+        gaps.retain(|gap| gap.to.is_none_or(|to| to > from));
+    }
+
+    Ok(gaps)
+}
+
 fn apply_limit_offset(query: &mut String, buffer_response: &FlushBufferResponse) {
     if let Some(limit) = buffer_response.normalized_limit() {
         *query = format!("{query} LIMIT {limit}");
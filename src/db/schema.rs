@@ -10,6 +10,8 @@ use tmi::{IrcMessageRef, Tag};
 use uuid::Uuid;
 
 pub const MESSAGES_STRUCTURED_TABLE: &str = "message_structured";
+pub const CONNECTION_EVENTS_TABLE: &str = "connection_event";
+pub const OPT_OUT_EVENTS_TABLE: &str = "opt_out_event";
 
 bitflags! {
     #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Default, Clone, Copy)]
@@ -92,6 +94,52 @@ pub struct StructuredMessage<'a> {
     text: Cow<'a, str>,
     pub message_flags: MessageFlags,
     pub extra_tags: Vec<(Cow<'a, str>, Cow<'a, str>)>,
+    /// Whether the message was later removed by a moderator. Not stored, only set when responding
+    #[serde(skip)]
+    pub deleted: bool,
+}
+
+#[derive(Row, Serialize, Deserialize, Debug, Clone)]
+pub struct ConnectionEvent {
+    pub channel_id: String,
+    pub timestamp: u64,
+    pub event_type: ConnectionEventType,
+}
+
+#[derive(Serialize_repr, Deserialize_repr, Debug, PartialEq, Clone, Copy)]
+#[repr(u8)]
+pub enum ConnectionEventType {
+    /// The channel was joined for the first time since startup
+    Connect = 0,
+    Disconnect = 1,
+    /// The channel was joined again after the connection was lost
+    Reconnect = 2,
+    /// The connection was lost at some point after this, but the exact time is not known
+    UncertainDisconnect = 3,
+}
+
+#[derive(Row, Serialize, Deserialize, Debug, Clone)]
+pub struct OptOutEvent {
+    pub user_id: String,
+    pub timestamp: u64,
+    pub event_type: OptOutEventType,
+}
+
+#[derive(Serialize_repr, Deserialize_repr, Debug, PartialEq, Clone, Copy)]
+#[repr(u8)]
+pub enum OptOutEventType {
+    OptOut = 0,
+    OptIn = 1,
+}
+
+/// Fields which identify a logged message, used for deduplication
+#[derive(Row, Deserialize, Debug, PartialEq, Eq, Hash, Clone)]
+pub struct MessageKey {
+    #[serde(with = "clickhouse::serde::uuid")]
+    pub id: Uuid,
+    pub timestamp: u64,
+    pub message_type: MessageType,
+    pub user_id: String,
 }
 
 #[derive(Row, Serialize, Deserialize, Debug)]
@@ -222,6 +270,7 @@ impl<'a> StructuredMessage<'a> {
             emotes,
             text,
             extra_tags,
+            deleted: false,
         })
     }
 
@@ -282,6 +331,23 @@ impl<'a> StructuredMessage<'a> {
         }
     }
 
+    pub fn uuid(&self) -> Option<Uuid> {
+        (!self.id.is_nil()).then_some(self.id)
+    }
+
+    pub fn has_id(&self, id: Uuid) -> bool {
+        !self.id.is_nil() && self.id == id
+    }
+
+    pub fn key(&self) -> MessageKey {
+        MessageKey {
+            id: self.id,
+            timestamp: self.timestamp,
+            message_type: self.message_type,
+            user_id: self.user_id.to_string(),
+        }
+    }
+
     pub fn display_name(&self) -> &str {
         if !self.display_name.is_empty() {
             &self.display_name
@@ -290,6 +356,23 @@ impl<'a> StructuredMessage<'a> {
         }
     }
 
+    pub fn extra_tag(&self, tag: Tag) -> Option<&str> {
+        self.extra_tags
+            .iter()
+            .find(|(name, _)| name == tag.as_str())
+            .map(|(_, value)| value.as_ref())
+    }
+
+    /// Stored text, including the system message of user notices
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Text sent by the user, without the system message of user notices
+    pub fn user_text(&self) -> &str {
+        extract_message_text(&self.text)
+    }
+
     pub fn all_tags(&self, escape: bool) -> Vec<(Tag, Cow<'_, str>)> {
         let mut tags = Vec::with_capacity(16);
 
@@ -479,8 +562,39 @@ impl<'a> StructuredMessage<'a> {
                 .into_iter()
                 .map(|(k, v)| (Cow::Owned(k.into_owned()), Cow::Owned(v.into_owned())))
                 .collect(),
+            deleted: self.deleted,
         }
     }
+
+    /// Approximate amount of memory used by the message, including its heap allocations
+    pub fn estimated_size(&self) -> usize {
+        let strings = [
+            &self.channel_id,
+            &self.channel_login,
+            &self.user_id,
+            &self.user_login,
+            &self.display_name,
+            &self.user_type,
+            &self.badge_info,
+            &self.client_nonce,
+            &self.emotes,
+            &self.automod_flags,
+            &self.text,
+        ];
+
+        size_of::<Self>()
+            + strings.iter().map(|value| value.len()).sum::<usize>()
+            + self
+                .badges
+                .iter()
+                .map(|badge| size_of::<Cow<str>>() + badge.len())
+                .sum::<usize>()
+            + self
+                .extra_tags
+                .iter()
+                .map(|(key, value)| 2 * size_of::<Cow<str>>() + key.len() + value.len())
+                .sum::<usize>()
+    }
 }
 
 fn escape_tag(value: &str) -> Cow<'_, str> {
@@ -506,7 +620,9 @@ fn escape_tag(value: &str) -> Cow<'_, str> {
     }
 }
 
-#[derive(Serialize_repr, Deserialize_repr, EnumString, Debug, PartialEq, Display, Clone, Copy)]
+#[derive(
+    Serialize_repr, Deserialize_repr, EnumString, Debug, PartialEq, Eq, Hash, Display, Clone, Copy,
+)]
 #[repr(u8)]
 #[strum(serialize_all = "UPPERCASE")]
 pub enum MessageType {
@@ -601,6 +717,7 @@ mod tests {
             automod_flags: "".into(),
             text: "+join 󠀀".into(),
             extra_tags: vec![],
+            deleted: false,
         };
 
         assert_eq!(expected_message, message);
@@ -725,6 +842,7 @@ mod tests {
             text: "xqc".into(),
             message_flags: MessageFlags::default(),
             extra_tags: vec![("target-user-id".into(), "71092938".into())],
+            deleted: false,
         };
         let reconstructed_irc = structured.to_raw_irc();
         assert_eq!(
@@ -0,0 +1,726 @@
+//! Search query language, compiled to a ClickHouse filter expression.
+//!
+//! Terms are combined with `AND` (also implied between terms), `OR` and `NOT`, and can be grouped with parentheses.
+//! Supported terms:
+//! - `word` or `"quoted phrase"`: case-insensitive substring of the message text
+//! - `/regex/`: RE2 regular expression, matching anywhere in the message text unless anchored with `^` and `$`.
+//!   A `/` without a closing one, like in `/me`, is a normal word
+//! - `from:user`: messages sent by a user login
+//! - `type:usernotice`: messages of a type
+//! - `badge:moderator`: messages whose sender has a badge
+//! - `flag:first-msg`: messages with a flag
+//! - `before:2024-01-31` and `after:2024-01-01`: messages sent before or after a date (or RFC 3339 timestamp)
+
+use super::schema::{MessageFlags, MessageType, StructuredMessage};
+use chrono::{DateTime, NaiveDate, Utc};
+use clickhouse::query::Query;
+use regex::Regex;
+use regex_syntax::ast::{
+    self, AssertionKind, Ast, ClassSetBinaryOp, ClassSetItem, Flag, FlagsItemKind, GroupKind,
+    RepetitionKind, RepetitionRange,
+};
+use std::{collections::HashMap, fmt, iter::Peekable, str::FromStr, vec::IntoIter};
+use tmi::Tag;
+
+/// A parsed search query
+#[derive(Debug, PartialEq)]
+pub struct SearchQuery {
+    expr: Expr,
+}
+
+/// Evaluates a search query against messages which are not in the database yet
+pub struct SearchMatcher<'a> {
+    expr: &'a Expr,
+    regexes: HashMap<&'a str, Regex>,
+}
+
+/// SQL filter expression with the values which have to be bound to it, in order
+pub struct CompiledSearch {
+    pub filter: String,
+    params: Vec<Param>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Term(Term),
+}
+
+#[derive(Debug, PartialEq)]
+enum Term {
+    Text(String),
+    Regex(String),
+    From(String),
+    Type(MessageType),
+    Badge(String),
+    Flag(MessageFlags),
+    Before(DateTime<Utc>),
+    After(DateTime<Utc>),
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Phrase(String),
+    Field(String, String),
+    Regex(String),
+    And,
+    Or,
+    Not,
+    OpenParen,
+    CloseParen,
+}
+
+enum Param {
+    String(String),
+    UInt(u64),
+    Timestamp(f64),
+}
+
+/// ClickHouse uses RE2, which limits counted repetitions to this
+const RE2_MAX_REPEAT: u32 = 1000;
+/// Queries are evaluated recursively, so their size is limited to keep the stack from overflowing
+const MAX_TOKENS: usize = 100;
+const MAX_NESTING: usize = 32;
+
+impl FromStr for SearchQuery {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        parse_query(input).map_err(|message| format!("Invalid search query: {message}"))
+    }
+}
+
+fn parse_query(input: &str) -> Result<SearchQuery, String> {
+    let tokens = tokenize(input)?;
+    if tokens.is_empty() {
+        return Err("empty search query".to_owned());
+    }
+    if tokens.len() > MAX_TOKENS {
+        return Err(format!("too many terms, at most {MAX_TOKENS} are allowed"));
+    }
+
+    let mut tokens = tokens.into_iter().peekable();
+    let expr = parse_or(&mut tokens, 0)?;
+
+    match tokens.next() {
+        Some(token) => Err(format!("unexpected {token}")),
+        None => Ok(SearchQuery { expr }),
+    }
+}
+
+impl SearchQuery {
+    pub fn compile(&self) -> CompiledSearch {
+        let mut compiled = CompiledSearch {
+            filter: String::new(),
+            params: Vec::new(),
+        };
+        compile_expr(&self.expr, &mut compiled);
+        compiled
+    }
+
+    pub fn matcher(&self) -> SearchMatcher<'_> {
+        let mut regexes = HashMap::new();
+        collect_regexes(&self.expr, &mut regexes);
+        SearchMatcher {
+            expr: &self.expr,
+            regexes,
+        }
+    }
+}
+
+impl SearchMatcher<'_> {
+    /// Has to give the same result as the compiled filter
+    pub fn matches(&self, msg: &StructuredMessage) -> bool {
+        self.matches_expr(self.expr, msg)
+    }
+
+    fn matches_expr(&self, expr: &Expr, msg: &StructuredMessage) -> bool {
+        match expr {
+            Expr::And(lhs, rhs) => self.matches_expr(lhs, msg) && self.matches_expr(rhs, msg),
+            Expr::Or(lhs, rhs) => self.matches_expr(lhs, msg) || self.matches_expr(rhs, msg),
+            Expr::Not(inner) => !self.matches_expr(inner, msg),
+            Expr::Term(term) => self.matches_term(term, msg),
+        }
+    }
+
+    fn matches_term(&self, term: &Term, msg: &StructuredMessage) -> bool {
+        match term {
+            Term::Text(text) => msg.text().to_lowercase().contains(&text.to_lowercase()),
+            Term::Regex(pattern) => self
+                .regexes
+                .get(pattern.as_str())
+                .is_some_and(|regex| regex.is_match(msg.text())),
+            Term::From(login) => msg.user_login == *login,
+            Term::Type(message_type) => msg.message_type == *message_type,
+            Term::Badge(name) => msg.badges.iter().any(|badge| {
+                badge
+                    .strip_prefix(name.as_str())
+                    .is_some_and(|version| version.starts_with('/'))
+            }),
+            Term::Flag(flag) => msg.message_flags.intersects(*flag),
+            Term::Before(date) => msg.timestamp < date.timestamp_millis() as u64,
+            Term::After(date) => msg.timestamp >= date.timestamp_millis() as u64,
+        }
+    }
+}
+
+fn collect_regexes<'a>(expr: &'a Expr, regexes: &mut HashMap<&'a str, Regex>) {
+    match expr {
+        Expr::And(lhs, rhs) | Expr::Or(lhs, rhs) => {
+            collect_regexes(lhs, regexes);
+            collect_regexes(rhs, regexes);
+        }
+        Expr::Not(inner) => collect_regexes(inner, regexes),
+        Expr::Term(Term::Regex(pattern)) => {
+            // The pattern has already been validated, it can only fail on size limits
+            if let Ok(regex) = Regex::new(pattern) {
+                regexes.insert(pattern, regex);
+            }
+        }
+        Expr::Term(_) => (),
+    }
+}
+
+impl CompiledSearch {
+    pub fn bind(&self, mut query: Query) -> Query {
+        for param in &self.params {
+            query = match param {
+                Param::String(value) => query.bind(value),
+                Param::UInt(value) => query.bind(value),
+                Param::Timestamp(value) => query.bind(value),
+            };
+        }
+        query
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::OpenParen);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::CloseParen);
+            }
+            '"' => {
+                chars.next();
+                tokens.push(Token::Phrase(read_quoted(&mut chars)?));
+            }
+            '/' if has_regex_end(&chars) => {
+                chars.next();
+                let mut pattern = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') if chars.peek() == Some(&'/') => {
+                            pattern.push(chars.next().unwrap());
+                        }
+                        Some('/') => break,
+                        Some(c) => pattern.push(c),
+                        None => return Err("unterminated regex".to_owned()),
+                    }
+                }
+                tokens.push(Token::Regex(pattern));
+            }
+            _ => {
+                let mut word = String::new();
+                let mut quoted_value = None;
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' {
+                        break;
+                    }
+                    chars.next();
+
+                    // Field values can be quoted to include spaces
+                    if c == ':' && chars.peek() == Some(&'"') {
+                        chars.next();
+                        quoted_value = Some(read_quoted(&mut chars)?);
+                        break;
+                    }
+                    word.push(c);
+                }
+
+                let token = match quoted_value {
+                    Some(value) => Token::Field(word.to_lowercase(), value),
+                    None => match word.as_str() {
+                        "AND" => Token::And,
+                        "OR" => Token::Or,
+                        "NOT" => Token::Not,
+                        _ => match word.split_once(':') {
+                            Some((field, value)) if is_field(field, value) => {
+                                Token::Field(field.to_lowercase(), value.to_owned())
+                            }
+                            _ => Token::Word(word),
+                        },
+                    },
+                };
+                tokens.push(token);
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Whether the `/` at the start of `chars` is closed by another unescaped `/`
+fn has_regex_end(chars: &Peekable<impl Iterator<Item = char> + Clone>) -> bool {
+    let mut chars = chars.clone();
+    chars.next();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'/') => {
+                chars.next();
+            }
+            '/' => return true,
+            _ => (),
+        }
+    }
+    false
+}
+
+fn read_quoted(chars: &mut Peekable<impl Iterator<Item = char>>) -> Result<String, String> {
+    let mut value = String::new();
+    loop {
+        match chars.next() {
+            Some('\\') => match chars.next() {
+                Some(c) => value.push(c),
+                None => return Err("unterminated quote".to_owned()),
+            },
+            Some('"') => return Ok(value),
+            Some(c) => value.push(c),
+            None => return Err("unterminated quote".to_owned()),
+        }
+    }
+}
+
+/// Words like URLs contain colons too, so only `name:value` is treated as a field
+fn is_field(name: &str, value: &str) -> bool {
+    !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphabetic())
+        && !value.is_empty()
+        && !value.starts_with("//")
+}
+
+type Tokens = Peekable<IntoIter<Token>>;
+
+fn parse_or(tokens: &mut Tokens, depth: usize) -> Result<Expr, String> {
+    let mut expr = parse_and(tokens, depth)?;
+    while tokens.next_if_eq(&Token::Or).is_some() {
+        let rhs = parse_and(tokens, depth)?;
+        expr = Expr::Or(Box::new(expr), Box::new(rhs));
+    }
+    Ok(expr)
+}
+
+fn parse_and(tokens: &mut Tokens, depth: usize) -> Result<Expr, String> {
+    let mut expr = parse_unary(tokens, depth)?;
+    loop {
+        match tokens.peek() {
+            Some(Token::And) => {
+                tokens.next();
+            }
+            Some(Token::Or | Token::CloseParen) | None => break,
+            // Terms next to each other are combined with AND
+            Some(_) => (),
+        }
+        let rhs = parse_unary(tokens, depth)?;
+        expr = Expr::And(Box::new(expr), Box::new(rhs));
+    }
+    Ok(expr)
+}
+
+fn parse_unary(tokens: &mut Tokens, depth: usize) -> Result<Expr, String> {
+    if depth > MAX_NESTING
+        && tokens
+            .peek()
+            .is_some_and(|token| matches!(token, Token::Not | Token::OpenParen))
+    {
+        return Err(format!(
+            "nested too deeply, at most {MAX_NESTING} levels are allowed"
+        ));
+    }
+
+    match tokens.next() {
+        Some(Token::Not) => Ok(Expr::Not(Box::new(parse_unary(tokens, depth + 1)?))),
+        Some(Token::OpenParen) => {
+            let expr = parse_or(tokens, depth + 1)?;
+            match tokens.next() {
+                Some(Token::CloseParen) => Ok(expr),
+                _ => Err("missing closing parenthesis".to_owned()),
+            }
+        }
+        Some(Token::Word(text) | Token::Phrase(text)) => Ok(Expr::Term(Term::Text(text))),
+        Some(Token::Regex(pattern)) => {
+            validate_regex(&pattern).map_err(|err| format!("invalid regex /{pattern}/: {err}"))?;
+            Ok(Expr::Term(Term::Regex(pattern)))
+        }
+        Some(Token::Field(field, value)) => parse_field(&field, value).map(Expr::Term),
+        Some(token) => Err(format!("unexpected {token}")),
+        None => Err("unexpected end of query".to_owned()),
+    }
+}
+
+/// Rejects syntax which is supported by the regex crate, but not by RE2
+fn validate_regex(pattern: &str) -> Result<(), String> {
+    let ast = ast::parse::Parser::new()
+        .parse(pattern)
+        .map_err(|err| err.to_string())?;
+    ast::visit(&ast, Re2Syntax)?;
+
+    // Some errors like invalid ranges are only found when translating the syntax tree
+    regex_syntax::Parser::new()
+        .parse(pattern)
+        .map_err(|err| err.to_string())?;
+    Ok(())
+}
+
+struct Re2Syntax;
+
+impl ast::Visitor for Re2Syntax {
+    type Output = ();
+    type Err = String;
+
+    fn finish(self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn visit_pre(&mut self, ast: &Ast) -> Result<(), String> {
+        match ast {
+            Ast::Flags(set_flags) => check_re2_flags(&set_flags.flags),
+            Ast::Group(group) => match &group.kind {
+                GroupKind::NonCapturing(flags) => check_re2_flags(flags),
+                _ => Ok(()),
+            },
+            Ast::Assertion(assertion) => match assertion.kind {
+                AssertionKind::StartLine
+                | AssertionKind::EndLine
+                | AssertionKind::StartText
+                | AssertionKind::EndText
+                | AssertionKind::WordBoundary
+                | AssertionKind::NotWordBoundary => Ok(()),
+                _ => Err(r"only \b and \B word boundaries are supported".to_owned()),
+            },
+            Ast::Repetition(repetition) => match repetition.op.kind {
+                RepetitionKind::Range(
+                    RepetitionRange::Exactly(count)
+                    | RepetitionRange::AtLeast(count)
+                    | RepetitionRange::Bounded(_, count),
+                ) if count > RE2_MAX_REPEAT => {
+                    Err(format!("repetitions can be at most {RE2_MAX_REPEAT}"))
+                }
+                _ => Ok(()),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn visit_class_set_item_pre(&mut self, item: &ClassSetItem) -> Result<(), String> {
+        match item {
+            ClassSetItem::Bracketed(_) => {
+                Err("nested character classes are not supported".to_owned())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn visit_class_set_binary_op_pre(&mut self, _: &ClassSetBinaryOp) -> Result<(), String> {
+        Err("character class operations are not supported".to_owned())
+    }
+}
+
+fn check_re2_flags(flags: &ast::Flags) -> Result<(), String> {
+    for item in &flags.items {
+        if let FlagsItemKind::Flag(flag @ (Flag::Unicode | Flag::CRLF | Flag::IgnoreWhitespace)) =
+            &item.kind
+        {
+            return Err(format!(
+                "unsupported flag {flag:?}, only i, m, s and U are supported"
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn parse_field(field: &str, value: String) -> Result<Term, String> {
+    match field {
+        "from" => Ok(Term::From(value.to_lowercase())),
+        "type" => MessageType::from_str(&value.to_uppercase())
+            .map(Term::Type)
+            .map_err(|_| format!("unknown message type {value}")),
+        "badge" => Ok(Term::Badge(value.to_lowercase())),
+        "flag" => MessageFlags::from_tag(&Tag::parse(&value.to_lowercase()))
+            .map(Term::Flag)
+            .ok_or_else(|| format!("unknown flag {value}")),
+        "before" => parse_date(&value).map(Term::Before),
+        "after" => parse_date(&value).map(Term::After),
+        _ => Err(format!("unknown field {field}")),
+    }
+}
+
+fn parse_date(value: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(date.and_time(Default::default()).and_utc());
+    }
+    DateTime::parse_from_rfc3339(value)
+        .map(|datetime| datetime.to_utc())
+        .map_err(|_| format!("invalid date {value}, expected YYYY-MM-DD or RFC 3339"))
+}
+
+fn compile_expr(expr: &Expr, compiled: &mut CompiledSearch) {
+    match expr {
+        Expr::And(lhs, rhs) | Expr::Or(lhs, rhs) => {
+            let operator = if matches!(expr, Expr::And(..)) {
+                "AND"
+            } else {
+                "OR"
+            };
+            compiled.filter.push('(');
+            compile_expr(lhs, compiled);
+            compiled.filter.push_str(&format!(" {operator} "));
+            compile_expr(rhs, compiled);
+            compiled.filter.push(')');
+        }
+        Expr::Not(inner) => {
+            compiled.filter.push_str("NOT ");
+            compile_expr(inner, compiled);
+        }
+        Expr::Term(term) => compile_term(term, compiled),
+    }
+}
+
+fn compile_term(term: &Term, compiled: &mut CompiledSearch) {
+    let (filter, param) = match term {
+        // Has to match the expression of the `text_ngram` index so it can be used
+        Term::Text(text) => (
+            "lowerUTF8(text) LIKE ?",
+            Param::String(format!("%{}%", escape_like(&text.to_lowercase()))),
+        ),
+        Term::Regex(pattern) => ("match(text, ?)", Param::String(pattern.clone())),
+        Term::From(login) => ("user_login = ?", Param::String(login.clone())),
+        Term::Type(message_type) => ("message_type = ?", Param::UInt(*message_type as u64)),
+        // Badges are stored with their version, such as `moderator/1`
+        Term::Badge(name) => (
+            "arrayExists(badge -> startsWith(badge, ?), badges)",
+            Param::String(format!("{name}/")),
+        ),
+        Term::Flag(flag) => (
+            "bitAnd(message_flags, ?) != 0",
+            Param::UInt(flag.bits() as u64),
+        ),
+        Term::Before(date) => (
+            "timestamp < ?",
+            Param::Timestamp(date.timestamp_millis() as f64 / 1000.0),
+        ),
+        Term::After(date) => (
+            "timestamp >= ?",
+            Param::Timestamp(date.timestamp_millis() as f64 / 1000.0),
+        ),
+    };
+    compiled.filter.push_str(filter);
+    compiled.params.push(param);
+}
+
+fn escape_like(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "{word}"),
+            Token::Phrase(phrase) => write!(f, "\"{phrase}\""),
+            Token::Field(field, value) => write!(f, "{field}:{value}"),
+            Token::Regex(pattern) => write!(f, "/{pattern}/"),
+            Token::And => f.write_str("AND"),
+            Token::Or => f.write_str("OR"),
+            Token::Not => f.write_str("NOT"),
+            Token::OpenParen => f.write_str("("),
+            Token::CloseParen => f.write_str(")"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Expr, SearchQuery, Term};
+    use crate::db::schema::{MessageFlags, MessageType, StructuredMessage, UnstructuredMessage};
+    use std::str::FromStr;
+
+    fn text(value: &str) -> Expr {
+        Expr::Term(Term::Text(value.to_owned()))
+    }
+
+    fn and(lhs: Expr, rhs: Expr) -> Expr {
+        Expr::And(Box::new(lhs), Box::new(rhs))
+    }
+
+    fn or(lhs: Expr, rhs: Expr) -> Expr {
+        Expr::Or(Box::new(lhs), Box::new(rhs))
+    }
+
+    fn parse(input: &str) -> Expr {
+        SearchQuery::from_str(input).unwrap().expr
+    }
+
+    #[test]
+    fn implicit_and_binds_tighter_than_or() {
+        assert_eq!(or(and(text("a"), text("b")), text("c")), parse("a b OR c"));
+        assert_eq!(
+            and(text("a"), or(text("b"), text("c"))),
+            parse("a AND (b OR c)")
+        );
+    }
+
+    #[test]
+    fn not_and_phrases() {
+        assert_eq!(
+            and(text("hello world"), Expr::Not(Box::new(text("bye")))),
+            parse(r#""hello world" NOT bye"#)
+        );
+    }
+
+    #[test]
+    fn fields() {
+        assert_eq!(
+            and(
+                and(
+                    Expr::Term(Term::From("forsen".to_owned())),
+                    Expr::Term(Term::Type(MessageType::UserNotice))
+                ),
+                and(
+                    Expr::Term(Term::Badge("moderator".to_owned())),
+                    Expr::Term(Term::Flag(MessageFlags::FIRST_MSG))
+                )
+            ),
+            parse("from:Forsen type:usernotice (badge:moderator flag:first-msg)")
+        );
+        assert_eq!(
+            Expr::Term(Term::From("some user".to_owned())),
+            parse(r#"from:"some user""#)
+        );
+    }
+
+    #[test]
+    fn words_with_colons() {
+        assert_eq!(text("https://example.com"), parse("https://example.com"));
+        assert_eq!(text("10:30"), parse("10:30"));
+    }
+
+    #[test]
+    fn regex() {
+        assert_eq!(
+            Expr::Term(Term::Regex("^!a/b$".to_owned())),
+            parse(r"/^!a\/b$/")
+        );
+        assert_eq!(
+            Expr::Term(Term::Regex(r"(?i)\bhi{2,3}\b".to_owned())),
+            parse(r"/(?i)\bhi{2,3}\b/")
+        );
+    }
+
+    #[test]
+    fn unclosed_slash_is_text() {
+        assert_eq!(and(text("/me"), text("waves")), parse("/me waves"));
+        assert_eq!(text("a/b"), parse("a/b"));
+    }
+
+    #[test]
+    fn compile() {
+        let compiled = SearchQuery::from_str("a OR NOT after:2024-01-01")
+            .unwrap()
+            .compile();
+        assert_eq!(
+            "(lowerUTF8(text) LIKE ? OR NOT timestamp >= ?)",
+            compiled.filter
+        );
+        assert_eq!(2, compiled.params.len());
+    }
+
+    #[test]
+    fn escape_like() {
+        assert_eq!(r"100\% a\_b c\\d", super::escape_like(r"100% a_b c\d"));
+    }
+
+    #[test]
+    fn invalid_queries() {
+        for input in [
+            "",
+            "(a",
+            "a)",
+            "a OR",
+            "NOT",
+            "\"unterminated",
+            "/(/",
+            "/[a&&b]/",
+            "/[a[bc]]/",
+            "/(?x)a b/",
+            r"/\<word\>/",
+            "/a{1001}/",
+            "type:nonsense",
+            "flag:nonsense",
+            "after:yesterday",
+            "unknown:value",
+        ] {
+            assert!(SearchQuery::from_str(input).is_err(), "{input}");
+        }
+    }
+
+    #[test]
+    fn size_limits() {
+        let nested_not = format!("{}a", "NOT ".repeat(20000));
+        let nested_parens = format!("{}a{}", "(".repeat(20000), ")".repeat(20000));
+        let long_and = "a ".repeat(20000);
+        for input in [nested_not, nested_parens, long_and] {
+            assert!(SearchQuery::from_str(&input).is_err());
+        }
+
+        let nested = format!("{}a{}", "(NOT ".repeat(16), ")".repeat(16));
+        assert!(SearchQuery::from_str(&nested).is_ok());
+    }
+
+    #[test]
+    fn matcher() {
+        let raw = "@badges=moderator/1,subscriber/12;first-msg=1;room-id=22484632;user-id=68136884;tmi-sent-ts=1709251274940 :supibot!supibot@supibot.tmi.twitch.tv PRIVMSG #forsen :Hello World";
+        let unstructured = UnstructuredMessage {
+            channel_id: "22484632",
+            user_id: "68136884",
+            timestamp: 1709251274940,
+            raw,
+        };
+        let msg = StructuredMessage::from_unstructured(&unstructured).unwrap();
+
+        let matches = |input: &str| {
+            SearchQuery::from_str(input)
+                .unwrap()
+                .matcher()
+                .matches(&msg)
+        };
+
+        assert!(matches("hello"));
+        assert!(matches(r#""o w" from:supibot"#));
+        assert!(matches("/W.rld$/ badge:moderator flag:first-msg"));
+        assert!(matches("type:privmsg after:2024-03-01 before:2024-03-02"));
+        assert!(matches("bye OR NOT badge:vip"));
+        assert!(!matches("/^World/"));
+        assert!(!matches("badge:mod"));
+        assert!(!matches("hello from:forsen"));
+    }
+}
@@ -0,0 +1,598 @@
+use super::schema::{MessageKey, StructuredMessage};
+use anyhow::Context;
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge, IntGauge};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+use tracing::{debug, error, info, warn};
+
+const SEGMENT_EXTENSION: &str = "ndjson";
+/// How much of the spilled segments a single read goes through, so reads during long outages stay bounded
+const MAX_SPILLED_READ_SIZE: u64 = 64 * 1024 * 1024;
+
+lazy_static! {
+    static ref SPOOL_SIZE_GAUGE: IntGauge = register_int_gauge!(
+        "rustlog_spool_size_bytes",
+        "Size of messages in the on-disk spool which have not been written to the database yet"
+    )
+    .unwrap();
+}
+
+/// Append-only on-disk copy of the write buffer, so messages survive database outages and crashes.
+///
+/// Messages are appended to the active segment as they arrive. When a batch is written, the active segment
+/// gets sealed, and all sealed segments are removed once the batch has been inserted.
+///
+/// Spilled segments hold messages which are only stored on disk, either because the in-memory buffer was full
+/// or because they were left over from a previous run. They are inserted directly from disk.
+pub struct Spool {
+    dir: PathBuf,
+    max_size: u64,
+    /// Segment new buffered messages are appended to
+    active: Option<(u64, File)>,
+    /// Segment new spilled messages are appended to
+    active_spill: Option<(u64, File)>,
+    next_segment: u64,
+    /// Segments whose messages are all part of the current or a previous batch
+    sealed: Vec<u64>,
+    spilled: Vec<u64>,
+    /// What spilled segments contain, so reads can skip segments
+    spilled_summaries: HashMap<u64, SegmentSummary>,
+    size: u64,
+    /// Whether the spool being full has already been logged
+    full_warned: bool,
+}
+
+impl Spool {
+    pub fn open(dir: impl Into<PathBuf>, max_size: u64) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Could not create spool directory {}", dir.display()))?;
+
+        let mut spilled = Vec::new();
+        let mut size = 0;
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            if let Some(segment) = parse_segment_name(&entry.path()) {
+                size += entry.metadata()?.len();
+                spilled.push(segment);
+            }
+        }
+        spilled.sort_unstable();
+
+        if !spilled.is_empty() {
+            info!(
+                "Found {} spool segments ({size} bytes) from a previous run, they will be written with the next batch",
+                spilled.len()
+            );
+        }
+
+        // Reads need to know what leftover segments contain to skip them
+        let mut spilled_summaries = HashMap::new();
+        for segment in &spilled {
+            let path = dir.join(format!("{segment}.{SEGMENT_EXTENSION}"));
+            let mut summary: Option<SegmentSummary> = None;
+            for msg in read_segment(&path, false)? {
+                match &mut summary {
+                    Some(summary) => summary.add(&msg),
+                    None => summary = Some(SegmentSummary::new(&msg)),
+                }
+            }
+            if let Some(summary) = summary {
+                spilled_summaries.insert(*segment, summary);
+            }
+        }
+
+        let next_segment = spilled.last().map_or(0, |last| last + 1);
+        SPOOL_SIZE_GAUGE.set(size as i64);
+
+        Ok(Self {
+            dir,
+            max_size,
+            active: None,
+            active_spill: None,
+            next_segment,
+            sealed: Vec::new(),
+            spilled,
+            spilled_summaries,
+            size,
+            full_warned: false,
+        })
+    }
+
+    /// Returns `false` if the spool is full
+    pub fn append(&mut self, message: &StructuredMessage) -> anyhow::Result<bool> {
+        self.write_line(message, false)
+    }
+
+    /// Stores a message only on disk, it gets inserted from the spool with the next batch.
+    /// Returns `false` if the spool is full.
+    pub fn spill(&mut self, message: &StructuredMessage) -> anyhow::Result<bool> {
+        self.write_line(message, true)
+    }
+
+    fn write_line(&mut self, message: &StructuredMessage, spill: bool) -> anyhow::Result<bool> {
+        if self.is_full() {
+            if !self.full_warned {
+                warn!("Spool is full ({} bytes)", self.size);
+                self.full_warned = true;
+            }
+            return Ok(false);
+        }
+
+        let mut line = serde_json::to_vec(message)?;
+        line.push(b'\n');
+
+        let next_segment = self.next_segment;
+        let path = self.segment_path(next_segment);
+        let active = if spill {
+            &mut self.active_spill
+        } else {
+            &mut self.active
+        };
+
+        if active.is_none() {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .context("Could not create spool segment")?;
+            *active = Some((next_segment, file));
+            self.next_segment += 1;
+        }
+
+        let (segment, file) = active.as_mut().expect("Active segment was just created");
+        file.write_all(&line)?;
+
+        if spill {
+            match self.spilled_summaries.get_mut(segment) {
+                Some(summary) => summary.add(message),
+                None => {
+                    self.spilled_summaries
+                        .insert(*segment, SegmentSummary::new(message));
+                }
+            }
+        }
+
+        self.size += line.len() as u64;
+        SPOOL_SIZE_GAUGE.set(self.size as i64);
+
+        Ok(true)
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.size >= self.max_size
+    }
+
+    /// Marks the messages appended so far as part of the batch that is about to be written
+    pub fn seal(&mut self) {
+        if let Some(segment) = close_segment(&mut self.active) {
+            self.sealed.push(segment);
+        }
+    }
+
+    /// Removes sealed segments after their messages have been written to the database
+    pub fn remove_sealed(&mut self) {
+        for segment in std::mem::take(&mut self.sealed) {
+            self.remove_segment(segment);
+        }
+    }
+
+    /// Returns all spilled segments, oldest first. New spilled messages go into a new segment after this.
+    pub fn take_spilled(&mut self) -> Vec<u64> {
+        if let Some(segment) = close_segment(&mut self.active_spill) {
+            self.spilled.push(segment);
+        }
+        self.spilled.clone()
+    }
+
+    pub fn segment_path(&self, segment: u64) -> PathBuf {
+        self.dir.join(format!("{segment}.{SEGMENT_EXTENSION}"))
+    }
+
+    /// Paths of the spilled segments which can contain messages of the channel in the time range, oldest first.
+    /// If they are larger than `MAX_SPILLED_READ_SIZE` in total, only the newest segments are included.
+    pub fn spilled_paths(
+        &self,
+        time_range: Option<&Range<u64>>,
+        channel_id: Option<&str>,
+    ) -> Vec<PathBuf> {
+        let mut read_size = 0;
+        let mut paths: Vec<PathBuf> = self
+            .spilled
+            .iter()
+            .chain(self.active_spill.iter().map(|(segment, _)| segment))
+            .rev()
+            .filter(|segment| {
+                // Segments without a summary have no valid messages
+                self.spilled_summaries.get(segment).is_some_and(|summary| {
+                    time_range.is_none_or(|time_range| {
+                        summary.oldest < time_range.end && summary.newest >= time_range.start
+                    }) && channel_id.is_none_or(|channel_id| summary.channels.contains(channel_id))
+                })
+            })
+            .map(|segment| self.segment_path(*segment))
+            .take_while(|path| {
+                if read_size >= MAX_SPILLED_READ_SIZE {
+                    warn!(
+                        "Spilled messages are too large to read, only the newest ones are included"
+                    );
+                    return false;
+                }
+                read_size += fs::metadata(path).map_or(0, |metadata| metadata.len());
+                true
+            })
+            .collect();
+        paths.reverse();
+        paths
+    }
+
+    /// Removes a spilled segment after its messages have been written to the database
+    pub fn remove_spilled(&mut self, segment: u64) {
+        self.spilled.retain(|spilled| *spilled != segment);
+        self.spilled_summaries.remove(&segment);
+        self.remove_segment(segment);
+    }
+
+    /// Removes all messages of a user from the spool, so nothing of an opted out user is kept on disk.
+    /// Returns how many messages were removed
+    pub fn remove_user(&mut self, user_id: &str) -> anyhow::Result<usize> {
+        let segments: Vec<u64> = self
+            .active
+            .iter()
+            .chain(&self.active_spill)
+            .map(|(segment, _)| *segment)
+            .chain(self.sealed.iter().copied())
+            .chain(self.spilled.iter().copied())
+            .collect();
+
+        self.retain(&segments, |msg| msg.user_id != user_id)
+    }
+
+    /// Removes messages which were discarded from the write buffer, so they don't get written after a restart.
+    /// Returns how many messages were removed
+    pub fn remove_buffered(&mut self, keys: &HashSet<MessageKey>) -> anyhow::Result<usize> {
+        let segments: Vec<u64> = self
+            .active
+            .iter()
+            .map(|(segment, _)| *segment)
+            .chain(self.sealed.iter().copied())
+            .collect();
+
+        self.retain(&segments, |msg| !keys.contains(&msg.key()))
+    }
+
+    fn retain(
+        &mut self,
+        segments: &[u64],
+        keep: impl Fn(&StructuredMessage) -> bool,
+    ) -> anyhow::Result<usize> {
+        let mut removed = 0;
+        for segment in segments {
+            removed += self.retain_in_segment(*segment, &keep)?;
+        }
+
+        SPOOL_SIZE_GAUGE.set(self.size as i64);
+        self.full_warned = self.is_full();
+        Ok(removed)
+    }
+
+    /// Rewrites a segment with only the messages which should be kept
+    fn retain_in_segment(
+        &mut self,
+        segment: u64,
+        keep: impl Fn(&StructuredMessage) -> bool,
+    ) -> anyhow::Result<usize> {
+        let path = self.segment_path(segment);
+        let temp_path = path.with_extension("tmp");
+        let previous_size = fs::metadata(&path)?.len();
+
+        let reader = BufReader::new(File::open(&path)?);
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        let (mut removed, mut size) = (0, 0);
+
+        for line in reader.lines() {
+            let line = line?;
+            // Invalid lines are kept as they are, they get skipped when reading the segment
+            if serde_json::from_str::<StructuredMessage>(&line).is_ok_and(|msg| !keep(&msg)) {
+                removed += 1;
+                continue;
+            }
+            writer.write_all(line.as_bytes())?;
+            writer.write_all(b"\n")?;
+            size += line.len() as u64 + 1;
+        }
+
+        if removed == 0 {
+            drop(writer);
+            fs::remove_file(&temp_path)?;
+            return Ok(0);
+        }
+
+        writer.into_inner()?.sync_data()?;
+        fs::rename(&temp_path, &path)
+            .with_context(|| format!("Could not replace spool segment {}", path.display()))?;
+        self.size = self.size.saturating_sub(previous_size) + size;
+
+        // The active segments still point to the replaced file
+        for (active_segment, file) in [&mut self.active, &mut self.active_spill]
+            .into_iter()
+            .flatten()
+        {
+            if *active_segment == segment {
+                *file = OpenOptions::new().append(true).open(&path)?;
+            }
+        }
+
+        debug!("Removed {removed} messages from spool segment {segment}");
+        Ok(removed)
+    }
+
+    fn remove_segment(&mut self, segment: u64) {
+        let path = self.segment_path(segment);
+        let len = fs::metadata(&path)
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+
+        match fs::remove_file(&path) {
+            Ok(()) => self.size = self.size.saturating_sub(len),
+            Err(err) => error!("Could not remove spool segment {}: {err}", path.display()),
+        }
+
+        debug!("Spool size is now {} bytes", self.size);
+        SPOOL_SIZE_GAUGE.set(self.size as i64);
+        self.full_warned = self.is_full();
+    }
+}
+
+/// Time range and channels of the messages in a segment
+struct SegmentSummary {
+    oldest: u64,
+    newest: u64,
+    channels: HashSet<String>,
+}
+
+impl SegmentSummary {
+    fn new(message: &StructuredMessage) -> Self {
+        Self {
+            oldest: message.timestamp,
+            newest: message.timestamp,
+            channels: HashSet::from([message.channel_id.to_string()]),
+        }
+    }
+
+    fn add(&mut self, message: &StructuredMessage) {
+        self.oldest = self.oldest.min(message.timestamp);
+        self.newest = self.newest.max(message.timestamp);
+        if !self.channels.contains(message.channel_id.as_ref()) {
+            self.channels.insert(message.channel_id.to_string());
+        }
+    }
+}
+
+/// Reads the messages of a segment one by one.
+/// Lines which can't be parsed are skipped, and only logged if `warn_invalid` is set.
+pub fn read_segment(
+    path: &Path,
+    warn_invalid: bool,
+) -> anyhow::Result<impl Iterator<Item = StructuredMessage<'static>>> {
+    let file = File::open(path)
+        .with_context(|| format!("Could not open spool segment {}", path.display()))?;
+    let path = path.to_owned();
+
+    let messages = BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(move |line| {
+            // The last line can be incomplete if the process was killed while writing it
+            match serde_json::from_str::<StructuredMessage>(&line) {
+                Ok(msg) => Some(msg.into_owned()),
+                Err(err) => {
+                    if warn_invalid {
+                        warn!("Skipping invalid line in spool {}: {err}", path.display());
+                    }
+                    None
+                }
+            }
+        });
+    Ok(messages)
+}
+
+/// Reads matching messages from spilled segments.
+/// Segments which were removed in the meantime have already been written to the database and are skipped.
+pub fn read_spilled(
+    paths: &[PathBuf],
+    filter: impl Fn(&StructuredMessage) -> bool,
+) -> Vec<StructuredMessage<'static>> {
+    paths
+        .iter()
+        // The active spill segment can end with a line which is still being written
+        .filter_map(|path| read_segment(path, false).ok())
+        .flatten()
+        .filter(|msg| filter(msg))
+        .collect()
+}
+
+fn close_segment(active: &mut Option<(u64, File)>) -> Option<u64> {
+    let (segment, file) = active.take()?;
+    if let Err(err) = file.sync_data() {
+        error!("Could not sync spool segment {segment}: {err}");
+    }
+    Some(segment)
+}
+
+fn parse_segment_name(path: &Path) -> Option<u64> {
+    if path.extension()? != SEGMENT_EXTENSION {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_segment, Spool};
+    use crate::db::schema::{StructuredMessage, UnstructuredMessage};
+    use pretty_assertions::assert_eq;
+    use std::{
+        fs,
+        io::Write,
+        path::{Path, PathBuf},
+    };
+
+    /// Spool directory which is removed when the test ends
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("rustlog-spool-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn message(user_id: &str, timestamp: u64) -> StructuredMessage<'static> {
+        let raw = format!("@room-id=1;user-id={user_id};tmi-sent-ts={timestamp} :user!user@user.tmi.twitch.tv PRIVMSG #channel :hello");
+        let unstructured = UnstructuredMessage {
+            channel_id: "1",
+            user_id,
+            timestamp,
+            raw: &raw,
+        };
+        StructuredMessage::from_unstructured(&unstructured)
+            .unwrap()
+            .into_owned()
+    }
+
+    fn segment_files(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn timestamps(spool: &Spool, segment: u64) -> Vec<u64> {
+        read_segment(&spool.segment_path(segment), true)
+            .unwrap()
+            .map(|msg| msg.timestamp)
+            .collect()
+    }
+
+    #[test]
+    fn append_seal_and_remove_sealed() {
+        let dir = TestDir::new("seal");
+        let mut spool = Spool::open(&dir.0, u64::MAX).unwrap();
+
+        assert!(spool.append(&message("a", 10)).unwrap());
+        assert!(spool.append(&message("b", 20)).unwrap());
+        spool.seal();
+        // Messages arriving while the batch is written go into a new segment
+        assert!(spool.append(&message("a", 30)).unwrap());
+        assert_eq!(vec!["0.ndjson", "1.ndjson"], segment_files(&dir.0));
+
+        spool.remove_sealed();
+
+        assert_eq!(vec!["1.ndjson"], segment_files(&dir.0));
+        assert_eq!(vec![30], timestamps(&spool, 1));
+        assert_eq!(
+            fs::metadata(spool.segment_path(1)).unwrap().len(),
+            spool.size
+        );
+    }
+
+    #[test]
+    fn reopen_with_leftover_segments() {
+        let dir = TestDir::new("reopen");
+        {
+            let mut spool = Spool::open(&dir.0, u64::MAX).unwrap();
+            spool.append(&message("a", 10)).unwrap();
+            spool.spill(&message("b", 20)).unwrap();
+            spool.seal();
+            spool.append(&message("c", 30)).unwrap();
+        }
+
+        let mut spool = Spool::open(&dir.0, u64::MAX).unwrap();
+
+        assert_eq!(3, spool.next_segment);
+        assert_eq!(vec![0, 1, 2], spool.take_spilled());
+
+        spool.append(&message("d", 40)).unwrap();
+        assert_eq!(vec![40], timestamps(&spool, 3));
+    }
+
+    #[test]
+    fn spilled_paths_of_leftover_segments() {
+        let dir = TestDir::new("spilled-paths");
+        {
+            let mut spool = Spool::open(&dir.0, u64::MAX).unwrap();
+            spool.spill(&message("a", 10)).unwrap();
+            spool.spill(&message("a", 20)).unwrap();
+            spool.take_spilled();
+            spool.spill(&message("b", 30)).unwrap();
+        }
+
+        let spool = Spool::open(&dir.0, u64::MAX).unwrap();
+
+        assert_eq!(
+            vec![spool.segment_path(0), spool.segment_path(1)],
+            spool.spilled_paths(None, None)
+        );
+        assert_eq!(
+            vec![spool.segment_path(1)],
+            spool.spilled_paths(Some(&(21..40)), Some("1"))
+        );
+        assert_eq!(
+            vec![spool.segment_path(0)],
+            spool.spilled_paths(Some(&(0..11)), None)
+        );
+        assert!(spool.spilled_paths(None, Some("2")).is_empty());
+    }
+
+    #[test]
+    fn retain_rewrites_active_segment() {
+        let dir = TestDir::new("retain");
+        let mut spool = Spool::open(&dir.0, u64::MAX).unwrap();
+        spool.append(&message("a", 10)).unwrap();
+        spool.append(&message("b", 20)).unwrap();
+        spool.append(&message("a", 30)).unwrap();
+
+        assert_eq!(2, spool.remove_user("a").unwrap());
+        assert_eq!(0, spool.remove_user("a").unwrap());
+
+        // Appending continues in the rewritten file
+        spool.append(&message("c", 40)).unwrap();
+        assert_eq!(vec![20, 40], timestamps(&spool, 0));
+        assert_eq!(
+            fs::metadata(spool.segment_path(0)).unwrap().len(),
+            spool.size
+        );
+        assert_eq!(vec!["0.ndjson"], segment_files(&dir.0));
+    }
+
+    #[test]
+    fn truncated_line_is_skipped() {
+        let dir = TestDir::new("truncated");
+        let spool = Spool::open(&dir.0, u64::MAX).unwrap();
+
+        let mut line = serde_json::to_vec(&message("a", 10)).unwrap();
+        line.push(b'\n');
+        let mut file = fs::File::create(spool.segment_path(0)).unwrap();
+        file.write_all(&line).unwrap();
+        file.write_all(&line[..line.len() / 2]).unwrap();
+
+        assert_eq!(vec![10], timestamps(&spool, 0));
+    }
+}
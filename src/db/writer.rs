@@ -1,22 +1,44 @@
-use super::schema::StructuredMessage;
-use crate::{db::schema::MESSAGES_STRUCTURED_TABLE, ShutdownRx};
+use super::{
+    message_index::MessageIndex,
+    schema::{MessageKey, StructuredMessage},
+    spool::{self, Spool},
+};
+use crate::{
+    config::{Config, OverflowPolicy},
+    db::schema::MESSAGES_STRUCTURED_TABLE,
+    ShutdownRx,
+};
 use anyhow::{anyhow, Context};
+use chrono::Utc;
 use clickhouse::Client;
 use lazy_static::lazy_static;
-use prometheus::{register_int_gauge, IntGauge};
-use std::{ops::Range, sync::Arc, time::Duration};
+use prometheus::{register_int_counter, register_int_gauge, IntCounter, IntGauge};
+use std::{
+    collections::HashSet,
+    ops::Range,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 use tokio::{
     sync::{
         mpsc::{channel, Sender},
-        RwLock,
+        Notify, RwLock,
     },
-    task::JoinHandle,
+    task::{spawn_blocking, JoinHandle},
     time::{sleep, Instant},
 };
-use tracing::{debug, error, info, trace};
+use tracing::{debug, error, info, trace, warn};
+use uuid::Uuid;
 
 const RETRY_COUNT: usize = 20;
 const RETRY_INTERVAL_SECONDS: u64 = 5;
+/// Fraction of the limits which is freed up when dropping old messages, so it doesn't happen on every new message
+const DROP_OLDEST_FRACTION: f64 = 0.1;
+/// How many spilled messages are read ahead of the insert
+const SPILLED_READ_QUEUE_SIZE: usize = 1000;
 
 lazy_static! {
     static ref BATCH_MSG_COUNT_GAGUE: IntGauge = register_int_gauge!(
@@ -24,11 +46,39 @@ lazy_static! {
         "How many messages are written to the database per batch"
     )
     .unwrap();
+    static ref BUFFER_MESSAGES_GAUGE: IntGauge = register_int_gauge!(
+        "rustlog_buffer_messages",
+        "How many messages are kept in memory until they are written"
+    )
+    .unwrap();
+    static ref BUFFER_SIZE_GAUGE: IntGauge = register_int_gauge!(
+        "rustlog_buffer_size_bytes",
+        "Estimated size of the messages kept in memory until they are written"
+    )
+    .unwrap();
+    static ref BUFFER_OLDEST_AGE_GAUGE: IntGauge = register_int_gauge!(
+        "rustlog_buffer_oldest_message_age_seconds",
+        "Age of the oldest message kept in memory"
+    )
+    .unwrap();
+    static ref BUFFER_DROPPED_COUNTER: IntCounter = register_int_counter!(
+        "rustlog_buffer_dropped_messages",
+        "How many messages were discarded because the buffer was full"
+    )
+    .unwrap();
 }
 
 #[derive(Default, Clone)]
 pub struct FlushBuffer {
-    messages: Arc<RwLock<Vec<StructuredMessage<'static>>>>,
+    /// Messages which have not been sent to the database yet
+    active: Arc<RwLock<MessageIndex>>,
+    /// Batch which is currently being inserted. It stays readable until the database has acknowledged it
+    in_flight: Arc<RwLock<Arc<MessageIndex>>>,
+    /// Estimated size of all buffered messages, including the batch in flight
+    size: Arc<AtomicU64>,
+    spool: Option<Arc<Mutex<Spool>>>,
+    /// Notified when the batch in flight has been written or put back
+    flushed: Arc<Notify>,
 }
 
 impl FlushBuffer {
@@ -37,15 +87,7 @@ impl FlushBuffer {
         time_range: Range<u64>,
         channel_id: &str,
     ) -> Vec<StructuredMessage<'static>> {
-        let msgs = self
-            .messages
-            .read()
-            .await
-            .iter()
-            .filter(|msg| time_range.contains(&msg.timestamp))
-            .filter(|msg| msg.channel_id == channel_id)
-            .cloned()
-            .collect::<Vec<_>>();
+        let msgs = self.lookup(time_range, channel_id, None).await;
         trace!("Read {} messages from flush buffer", msgs.len());
         msgs
     }
@@ -56,55 +98,409 @@ impl FlushBuffer {
         channel_id: &str,
         user_id: &str,
     ) -> Vec<StructuredMessage<'static>> {
-        let msgs = self
-            .messages
-            .read()
-            .await
-            .iter()
-            .filter(|msg| time_range.contains(&msg.timestamp))
-            .filter(|msg| msg.channel_id == channel_id && msg.user_id == user_id)
-            .cloned()
-            .collect::<Vec<_>>();
+        let msgs = self.lookup(time_range, channel_id, Some(user_id)).await;
         trace!("Read {} messages from flush buffer", msgs.len());
         msgs
     }
+
+    pub async fn message_by_id(&self, id: Uuid) -> Option<StructuredMessage<'static>> {
+        let buffered = {
+            let in_flight = self.in_flight.read().await;
+            let active = self.active.read().await;
+
+            let msg = in_flight
+                .messages()
+                .chain(active.messages())
+                .find(|msg| msg.has_id(id))
+                .cloned();
+            msg
+        };
+
+        match buffered {
+            Some(msg) => Some(msg),
+            None => self
+                .spilled_messages(None, None, move |msg| msg.has_id(id))
+                .await
+                .into_iter()
+                .next(),
+        }
+    }
+
+    /// Returns matching messages from the batch in flight, the active buffer and the spilled messages,
+    /// ordered by timestamp
+    async fn lookup(
+        &self,
+        time_range: Range<u64>,
+        channel_id: &str,
+        user_id: Option<&str>,
+    ) -> Vec<StructuredMessage<'static>> {
+        let mut msgs: Vec<_> = {
+            let in_flight = self.in_flight.read().await;
+            let active = self.active.read().await;
+
+            in_flight
+                .range(channel_id, user_id, time_range.clone())
+                .chain(active.range(channel_id, user_id, time_range.clone()))
+                .cloned()
+                .collect()
+        };
+
+        let spilled_filter = {
+            let (channel_id, user_id) = (channel_id.to_owned(), user_id.map(str::to_owned));
+            let time_range = time_range.clone();
+            move |msg: &StructuredMessage| {
+                msg.channel_id == channel_id
+                    && user_id
+                        .as_ref()
+                        .is_none_or(|user_id| msg.user_id == *user_id)
+                    && time_range.contains(&msg.timestamp)
+            }
+        };
+        msgs.extend(
+            self.spilled_messages(Some(time_range), Some(channel_id), spilled_filter)
+                .await,
+        );
+
+        // All sources are sorted already, but backfilled messages can make them overlap
+        msgs.sort_by_key(|msg| msg.timestamp);
+        msgs
+    }
+
+    /// Messages which are only stored in the spool, because the buffer was full when they arrived
+    async fn spilled_messages(
+        &self,
+        time_range: Option<Range<u64>>,
+        channel_id: Option<&str>,
+        filter: impl Fn(&StructuredMessage) -> bool + Send + 'static,
+    ) -> Vec<StructuredMessage<'static>> {
+        let Some(spool) = &self.spool else {
+            return Vec::new();
+        };
+        let paths = spool
+            .lock()
+            .unwrap()
+            .spilled_paths(time_range.as_ref(), channel_id);
+        if paths.is_empty() {
+            return Vec::new();
+        }
+
+        spawn_blocking(move || spool::read_spilled(&paths, filter))
+            .await
+            .unwrap_or_else(|err| {
+                error!("Could not read spilled messages: {err}");
+                Vec::new()
+            })
+    }
+
+    /// Drops all buffered and spooled messages of a user before they reach the database.
+    /// A batch which is already being inserted is waited for, so a deletion afterwards also covers it.
+    pub async fn remove_user_messages(&self, user_id: &str) {
+        self.remove_spooled_user_messages(user_id).await;
+
+        let mut in_flight = self.in_flight.write().await;
+        let mut active = self.active.write().await;
+        let previous_len = in_flight.len() + active.len();
+
+        Arc::make_mut(&mut in_flight).retain(|msg| msg.user_id != user_id);
+        active.retain(|msg| msg.user_id != user_id);
+
+        self.size.store(
+            in_flight
+                .messages()
+                .chain(active.messages())
+                .map(|msg| msg.estimated_size() as u64)
+                .sum(),
+            Ordering::Relaxed,
+        );
+        debug!(
+            "Removed {} messages of user {user_id} from flush buffer",
+            previous_len - in_flight.len() - active.len()
+        );
+        drop((in_flight, active));
+
+        self.wait_for_in_flight().await;
+    }
+
+    /// Waits until the batch in flight has been written or put back into the active messages
+    async fn wait_for_in_flight(&self) {
+        let flushed = self.flushed.notified();
+        tokio::pin!(flushed);
+        flushed.as_mut().enable();
+
+        if self.in_flight.read().await.len() > 0 {
+            flushed.await;
+        }
+    }
+
+    async fn remove_spooled_user_messages(&self, user_id: &str) {
+        let Some(spool) = self.spool.clone() else {
+            return;
+        };
+
+        let owned_user_id = user_id.to_owned();
+        let result =
+            spawn_blocking(move || spool.lock().unwrap().remove_user(&owned_user_id)).await;
+        match result {
+            Ok(Ok(removed)) => debug!("Removed {removed} messages of user {user_id} from spool"),
+            Ok(Err(err)) => {
+                error!("Could not remove messages of user {user_id} from spool: {err:#}")
+            }
+            Err(err) => error!("Spool removal task failed: {err}"),
+        }
+    }
+
+    async fn push(&self, message: StructuredMessage<'static>) {
+        let mut active = self.active.write().await;
+        self.size
+            .fetch_add(message.estimated_size() as u64, Ordering::Relaxed);
+        active.push(message);
+    }
+
+    /// Total amount of buffered messages, including the batch in flight
+    async fn len(&self) -> usize {
+        self.in_flight.read().await.len() + self.active.read().await.len()
+    }
+
+    async fn active_len(&self) -> usize {
+        self.active.read().await.len()
+    }
+
+    /// Moves the active messages into a new batch which is about to be inserted
+    async fn begin_flush(&self) -> Arc<MessageIndex> {
+        let mut in_flight = self.in_flight.write().await;
+        let mut active = self.active.write().await;
+        *in_flight = Arc::new(std::mem::take(&mut *active));
+        in_flight.clone()
+    }
+
+    /// Removes the batch in flight once it has been written.
+    /// Until then, readers can briefly see its messages both in the database and in the buffer.
+    async fn complete_flush(&self) {
+        let batch = std::mem::take(&mut *self.in_flight.write().await);
+        let size: u64 = batch
+            .messages()
+            .map(|msg| msg.estimated_size() as u64)
+            .sum();
+        self.size.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    /// Puts the batch in flight back in front of the active messages, so it's retried with the next flush
+    async fn requeue_flush(&self) {
+        let mut in_flight = self.in_flight.write().await;
+        let mut active = self.active.write().await;
+        let batch = std::mem::take(&mut *in_flight);
+        active.prepend(Arc::unwrap_or_clone(batch));
+    }
+
+    /// Discards the oldest active messages until the buffer is below the given limits.
+    /// Returns the keys of the discarded messages.
+    async fn drop_oldest(&self, max_messages: usize, max_bytes: u64) -> HashSet<MessageKey> {
+        let in_flight_len = self.in_flight.read().await.len();
+        let mut active = self.active.write().await;
+        let mut size = self.size.load(Ordering::Relaxed);
+
+        let mut count = 0;
+        for msg in active.messages() {
+            if in_flight_len + active.len() - count <= max_messages && size <= max_bytes {
+                break;
+            }
+            size = size.saturating_sub(msg.estimated_size() as u64);
+            count += 1;
+        }
+
+        let keys = active
+            .drain_front(count)
+            .iter()
+            .map(StructuredMessage::key)
+            .collect();
+        self.size.store(size, Ordering::Relaxed);
+        keys
+    }
+
+    async fn update_gauges(&self) {
+        let in_flight = self.in_flight.read().await;
+        let active = self.active.read().await;
+
+        let oldest = in_flight.messages().next().or(active.messages().next());
+        let oldest_age = oldest.map_or(0, |msg| {
+            let now = Utc::now().timestamp_millis() as u64;
+            now.saturating_sub(msg.timestamp) / 1000
+        });
+
+        BUFFER_MESSAGES_GAUGE.set((in_flight.len() + active.len()) as i64);
+        BUFFER_SIZE_GAUGE.set(self.size.load(Ordering::Relaxed) as i64);
+        BUFFER_OLDEST_AGE_GAUGE.set(oldest_age as i64);
+    }
+}
+
+/// When the active messages get written to the database
+#[derive(Debug, Clone, Copy)]
+pub struct FlushTriggers {
+    pub interval: Duration,
+    pub max_messages: Option<usize>,
+    pub max_bytes: Option<u64>,
+}
+
+impl FlushTriggers {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            interval: Duration::from_secs(config.clickhouse_flush_interval),
+            max_messages: config.clickhouse_flush_max_messages,
+            max_bytes: config.clickhouse_flush_max_bytes,
+        }
+    }
+
+    /// Whether the active messages are large enough to be flushed before the interval has passed
+    async fn is_reached(&self, buffer: &FlushBuffer) -> bool {
+        let messages_reached = match self.max_messages {
+            Some(max_messages) => buffer.active_len().await >= max_messages,
+            None => false,
+        };
+        // The size is tracked for all buffered messages, which only consist of active ones while nothing is in flight
+        let bytes_reached = self
+            .max_bytes
+            .is_some_and(|max_bytes| buffer.size.load(Ordering::Relaxed) >= max_bytes);
+
+        messages_reached || bytes_reached
+    }
+}
+
+/// Limits of the in-memory write buffer
+#[derive(Debug, Clone, Copy)]
+pub struct BufferLimits {
+    pub max_messages: Option<usize>,
+    pub max_bytes: Option<u64>,
+    pub overflow_policy: OverflowPolicy,
+    /// How many messages can wait to be buffered before sources are blocked
+    pub queue_size: usize,
+}
+
+impl BufferLimits {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            max_messages: config.buffer_max_messages,
+            max_bytes: config.buffer_max_bytes,
+            overflow_policy: config.buffer_overflow_policy,
+            queue_size: config.buffer_queue_size,
+        }
+    }
+
+    async fn is_reached(&self, buffer: &FlushBuffer) -> bool {
+        let messages_reached = match self.max_messages {
+            Some(max_messages) => buffer.len().await >= max_messages,
+            None => false,
+        };
+        let bytes_reached = self
+            .max_bytes
+            .is_some_and(|max_bytes| buffer.size.load(Ordering::Relaxed) >= max_bytes);
+
+        messages_reached || bytes_reached
+    }
+
+    /// Discards the oldest buffered messages, also from the spool so they are not written after a restart
+    async fn drop_oldest(&self, buffer: &FlushBuffer, spool: Option<&Arc<Mutex<Spool>>>) {
+        let target = |max: f64| (max * (1.0 - DROP_OLDEST_FRACTION)) as u64;
+        let max_messages = self
+            .max_messages
+            .map_or(usize::MAX, |max| target(max as f64) as usize);
+        let max_bytes = self.max_bytes.map_or(u64::MAX, |max| target(max as f64));
+
+        let dropped = buffer.drop_oldest(max_messages, max_bytes).await;
+        BUFFER_DROPPED_COUNTER.inc_by(dropped.len() as u64);
+        warn!("Discarded {} buffered messages", dropped.len());
+
+        if let Some(spool) = spool.cloned() {
+            let result =
+                spawn_blocking(move || spool.lock().unwrap().remove_buffered(&dropped)).await;
+            match result {
+                Ok(Ok(_)) => (),
+                Ok(Err(err)) => error!("Could not remove discarded messages from spool: {err:#}"),
+                Err(err) => error!("Spool removal task failed: {err}"),
+            }
+        }
+    }
 }
 
 pub async fn create_writer(
     db: Client,
+    config: Arc<Config>,
     mut shutdown_rx: ShutdownRx,
-    flush_interval: u64,
+    triggers: FlushTriggers,
+    limits: BufferLimits,
+    spool: Option<Spool>,
 ) -> anyhow::Result<(
     Sender<StructuredMessage<'static>>,
     FlushBuffer,
     JoinHandle<()>,
 )> {
-    let (tx, mut rx) = channel(1000);
+    if limits.overflow_policy == OverflowPolicy::Spill && spool.is_none() {
+        return Err(anyhow!(
+            "The spill buffer overflow policy requires spoolPath to be set"
+        ));
+    }
+    let spool = spool.map(|spool| Arc::new(Mutex::new(spool)));
 
-    let flush_buffer = FlushBuffer::default();
+    let (tx, mut rx) = channel(limits.queue_size);
+
+    let flush_buffer = FlushBuffer {
+        spool: spool.clone(),
+        ..Default::default()
+    };
     let flush_buffer_clone = flush_buffer.clone();
 
     let handle = tokio::spawn(async move {
-        let timeout = tokio::time::sleep(Duration::from_secs(flush_interval));
+        let timeout = tokio::time::sleep(triggers.interval);
         tokio::pin!(timeout);
 
+        // Only one batch is inserted at a time, new messages keep being buffered in the meantime
+        let mut in_flight: Option<JoinHandle<()>> = None;
+        let mut limit_reached = false;
+        let mut blocked = false;
+
         loop {
             tokio::select! {
                 _ = &mut timeout => {
-                    timeout.as_mut().reset(Instant::now() + Duration::from_secs(flush_interval));
-                    if let Err(err) = write_chunk_with_retry(&db, &flush_buffer).await {
-                        error!("Could not write messages: {err}");
+                    timeout.as_mut().reset(Instant::now() + triggers.interval);
+                    if in_flight.is_none() {
+                        in_flight = Some(start_flush(&db, &config, &flush_buffer, spool.as_ref()).await);
+                    } else {
+                        debug!("Previous batch is still being written, skipping flush");
                     }
+                    flush_buffer.update_gauges().await;
                 }
-                Some(msg) = rx.recv() => {
-                    flush_buffer.messages.write().await.push(msg);
+                _ = wait_for_flush(&mut in_flight), if in_flight.is_some() => {
+                    in_flight = None;
+                    flush_buffer.update_gauges().await;
+                    blocked = false;
+                }
+                Some(msg) = rx.recv(), if !blocked => {
+                    let is_full = limits.is_reached(&flush_buffer).await;
+                    if is_full && !limit_reached {
+                        warn!(
+                            "Write buffer limits have been reached, applying the {:?} overflow policy",
+                            limits.overflow_policy
+                        );
+                    }
+                    limit_reached = is_full;
+
+                    if is_full {
+                        handle_overflow(msg, &limits, &flush_buffer, spool.as_ref()).await;
+                        blocked = limits.overflow_policy == OverflowPolicy::Block;
+                    } else {
+                        buffer_message(msg, &flush_buffer, spool.as_ref()).await;
+                    }
+
+                    if in_flight.is_none() && triggers.is_reached(&flush_buffer).await {
+                        timeout.as_mut().reset(Instant::now() + triggers.interval);
+                        in_flight = Some(start_flush(&db, &config, &flush_buffer, spool.as_ref()).await);
+                    }
                 }
                 Ok(()) = shutdown_rx.changed() => {
                     info!("Flushing database write buffer");
 
-                    if let Err(err) = write_chunk_with_retry(&db, &flush_buffer).await {
-                        error!("Could not flush messages: {err}");
-                    }
+                    wait_for_flush(&mut in_flight).await;
+                    let mut last_flush = Some(start_flush(&db, &config, &flush_buffer, spool.as_ref()).await);
+                    wait_for_flush(&mut last_flush).await;
 
                     break;
                 }
@@ -115,9 +511,158 @@ pub async fn create_writer(
     Ok((tx, flush_buffer_clone, handle))
 }
 
-async fn write_chunk_with_retry(db: &Client, buffer: &FlushBuffer) -> anyhow::Result<()> {
+async fn wait_for_flush(in_flight: &mut Option<JoinHandle<()>>) {
+    if let Some(handle) = in_flight {
+        if let Err(err) = handle.await {
+            error!("Flush task failed: {err}");
+        }
+    }
+}
+
+async fn buffer_message(
+    msg: StructuredMessage<'static>,
+    buffer: &FlushBuffer,
+    spool: Option<&Arc<Mutex<Spool>>>,
+) {
+    if let Some(spool) = spool {
+        if let Err(err) = spool.lock().unwrap().append(&msg) {
+            error!("Could not write message to spool: {err:#}");
+        }
+    }
+    buffer.push(msg).await;
+}
+
+async fn handle_overflow(
+    msg: StructuredMessage<'static>,
+    limits: &BufferLimits,
+    buffer: &FlushBuffer,
+    spool: Option<&Arc<Mutex<Spool>>>,
+) {
+    match limits.overflow_policy {
+        OverflowPolicy::Spill => {
+            let spilled = spool
+                .expect("Spool is required for spilling")
+                .lock()
+                .unwrap()
+                .spill(&msg);
+            match spilled {
+                Ok(true) => return,
+                Ok(false) => (),
+                Err(err) => error!("Could not spill message: {err:#}"),
+            }
+            // Fall back to dropping messages if the spool can't take any more
+            limits.drop_oldest(buffer, spool).await;
+        }
+        OverflowPolicy::DropOldest => limits.drop_oldest(buffer, spool).await,
+        // The message which reached the limit is still kept, new ones are held back until the next flush
+        OverflowPolicy::Block => (),
+    }
+    buffer_message(msg, buffer, spool).await;
+}
+
+/// Swaps out the active messages and inserts them in the background.
+///
+/// Spooled messages are only removed once they have been inserted.
+/// If the process stops before that, they are written again on the next start.
+async fn start_flush(
+    db: &Client,
+    config: &Arc<Config>,
+    buffer: &FlushBuffer,
+    spool: Option<&Arc<Mutex<Spool>>>,
+) -> JoinHandle<()> {
+    if let Some(spool) = spool {
+        spool.lock().unwrap().seal();
+    }
+    let batch = buffer.begin_flush().await;
+
+    let db = db.clone();
+    let config = config.clone();
+    let buffer = buffer.clone();
+    let spool = spool.cloned();
+
+    tokio::spawn(async move {
+        let result = write_batch_with_retry(&db, &config, &buffer, &batch).await;
+        drop(batch);
+        if result.is_err() {
+            buffer.requeue_flush().await;
+        }
+        buffer.flushed.notify_waiters();
+
+        if let Err(err) = result {
+            error!("Could not write messages: {err}");
+            return;
+        }
+
+        if let Some(spool) = spool {
+            spool.lock().unwrap().remove_sealed();
+            if let Err(err) = write_spilled(&db, &config, &spool).await {
+                error!("Could not write spilled messages: {err:#}");
+            }
+        }
+    })
+}
+
+/// Inserts spilled messages straight from disk, without loading them into memory.
+/// Segments which fail are kept and retried with the next flush.
+/// Messages of users who opted out in the meantime are skipped, which also covers segments left over from a crash.
+async fn write_spilled(
+    db: &Client,
+    config: &Arc<Config>,
+    spool: &Arc<Mutex<Spool>>,
+) -> anyhow::Result<()> {
+    let segments = spool.lock().unwrap().take_spilled();
+
+    for segment in segments {
+        let started_at = Instant::now();
+        let path = spool.lock().unwrap().segment_path(segment);
+
+        // The segment is read on a blocking thread and passed over in small chunks
+        let (message_tx, mut message_rx) = channel(SPILLED_READ_QUEUE_SIZE);
+        let config = config.clone();
+        let reader = spawn_blocking(move || -> anyhow::Result<()> {
+            for message in spool::read_segment(&path, true)? {
+                if config.opt_out.contains_key(message.user_id.as_ref()) {
+                    continue;
+                }
+                if message_tx.blocking_send(message).is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        });
+
+        let mut insert = db.insert(MESSAGES_STRUCTURED_TABLE)?;
+        let mut count = 0;
+        while let Some(message) = message_rx.recv().await {
+            insert
+                .write(&message)
+                .await
+                .context("Could not write row")?;
+            count += 1;
+        }
+        reader.await??;
+        insert.end().await.context("Could not end insert")?;
+
+        debug!(
+            "{count} spilled messages have been inserted (took {}ms)",
+            started_at.elapsed().as_millis()
+        );
+
+        let spool = spool.clone();
+        spawn_blocking(move || spool.lock().unwrap().remove_spilled(segment)).await?;
+    }
+
+    Ok(())
+}
+
+async fn write_batch_with_retry(
+    db: &Client,
+    config: &Config,
+    buffer: &FlushBuffer,
+    batch: &MessageIndex,
+) -> anyhow::Result<()> {
     for attempt in 1..=RETRY_COUNT {
-        match write_chunk(db, buffer).await {
+        match write_batch(db, config, buffer, batch).await {
             Ok(()) => {
                 if attempt > 1 {
                     debug!("Insert succeeded on attempt {attempt}");
@@ -135,27 +680,33 @@ async fn write_chunk_with_retry(db: &Client, buffer: &FlushBuffer) -> anyhow::Re
     ))
 }
 
-async fn write_chunk(db: &Client, buffer: &FlushBuffer) -> anyhow::Result<()> {
-    let messages_read_guard = buffer.messages.read().await;
-
+/// Users who opted out after their messages were buffered are skipped
+async fn write_batch(
+    db: &Client,
+    config: &Config,
+    buffer: &FlushBuffer,
+    batch: &MessageIndex,
+) -> anyhow::Result<()> {
     let started_at = Instant::now();
 
     let mut insert = db.insert(MESSAGES_STRUCTURED_TABLE)?;
-    for message in messages_read_guard.iter() {
+    for message in batch
+        .messages()
+        .filter(|msg| !config.opt_out.contains_key(msg.user_id.as_ref()))
+    {
         insert.write(message).await.context("Could not write row")?;
     }
-    drop(messages_read_guard);
 
-    let mut messages_write_guard = buffer.messages.write().await;
+    // The batch stays readable from the buffer while it's committed, so slow inserts don't block reads and intake
     insert.end().await.context("Could not end insert")?;
+    buffer.complete_flush().await;
 
     debug!(
         "{} messages have been inserted (took {}ms)",
-        messages_write_guard.len(),
+        batch.len(),
         started_at.elapsed().as_millis()
     );
-    BATCH_MSG_COUNT_GAGUE.set(messages_write_guard.len().try_into().unwrap());
-    messages_write_guard.clear();
+    BATCH_MSG_COUNT_GAGUE.set(batch.len().try_into().unwrap());
 
     Ok(())
 }
@@ -0,0 +1,328 @@
+use crate::db::schema::{MessageType, StructuredMessage};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::{collections::BTreeMap, str::FromStr};
+use strum::IntoStaticStr;
+use tmi::Tag;
+
+/// A subscription, gift, raid or other channel event parsed from a user notice
+#[derive(Serialize, JsonSchema, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelEvent {
+    pub timestamp: DateTime<Utc>,
+    pub id: Option<String>,
+    pub user_id: String,
+    pub user_login: String,
+    pub display_name: String,
+    /// Message the user attached to the event, such as a resub message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Serialize, JsonSchema, Debug, PartialEq, IntoStaticStr)]
+#[serde(tag = "type", rename_all = "camelCase")]
+#[strum(serialize_all = "camelCase")]
+pub enum EventKind {
+    Sub(Subscription),
+    Resub(Subscription),
+    #[serde(rename_all = "camelCase")]
+    SubGift {
+        /// `Prime`, `1000`, `2000` or `3000`
+        plan: String,
+        recipient_id: String,
+        recipient_login: String,
+        recipient_display_name: String,
+        /// Cumulative months of the recipient
+        months: Option<u32>,
+        /// How many months were gifted at once
+        gift_months: Option<u32>,
+        /// How many subs the gifter has gifted in the channel in total
+        sender_count: Option<u32>,
+    },
+    #[serde(rename_all = "camelCase")]
+    MysteryGift {
+        plan: String,
+        count: u32,
+        sender_count: Option<u32>,
+    },
+    #[serde(rename_all = "camelCase")]
+    GiftUpgrade {
+        /// Not set if the sub was gifted anonymously
+        sender_login: Option<String>,
+    },
+    PrimeUpgrade {
+        plan: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Raid {
+        viewer_count: u32,
+    },
+    Announcement {
+        color: Option<String>,
+    },
+    BitsBadgeTier {
+        threshold: u32,
+    },
+    /// Any other kind of user notice, such as rituals
+    #[serde(rename_all = "camelCase")]
+    Other {
+        msg_id: String,
+    },
+}
+
+#[derive(Serialize, JsonSchema, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Subscription {
+    /// `Prime`, `1000`, `2000` or `3000`
+    pub plan: String,
+    pub plan_name: Option<String>,
+    pub cumulative_months: Option<u32>,
+    /// Only set if the user chose to share their streak
+    pub streak_months: Option<u32>,
+    /// How many months were bought at once
+    pub multimonth_duration: Option<u32>,
+    pub was_gifted: bool,
+}
+
+impl ChannelEvent {
+    /// Returns `None` for messages which are not user notices
+    pub fn from_structured(msg: &StructuredMessage) -> Option<anyhow::Result<Self>> {
+        if msg.message_type != MessageType::UserNotice {
+            return None;
+        }
+        let msg_id = msg.extra_tag(Tag::MsgId).unwrap_or_default();
+
+        Some(Self::parse(msg, msg_id).with_context(|| format!("Invalid {msg_id} user notice")))
+    }
+
+    fn parse(msg: &StructuredMessage, msg_id: &str) -> anyhow::Result<Self> {
+        let tag = |tag| msg.extra_tag(tag).map(str::to_owned);
+
+        let kind = match msg_id {
+            "sub" | "resub" => {
+                let subscription = Subscription {
+                    plan: required_tag(msg, Tag::MsgParamSubPlan)?,
+                    plan_name: tag(Tag::MsgParamSubPlanName),
+                    cumulative_months: number_tag(msg, Tag::MsgParamCumulativeMonths)?,
+                    streak_months: match msg.extra_tag(Tag::MsgParamShouldShareStreak) {
+                        Some("1") => number_tag(msg, Tag::MsgParamStreakMonths)?,
+                        _ => None,
+                    },
+                    multimonth_duration: number_tag(msg, Tag::MsgParamMultimonthDuration)?,
+                    was_gifted: msg.extra_tag(Tag::MsgParamWasGifted) == Some("true"),
+                };
+                if msg_id == "sub" {
+                    EventKind::Sub(subscription)
+                } else {
+                    EventKind::Resub(subscription)
+                }
+            }
+            "subgift" | "anonsubgift" => EventKind::SubGift {
+                plan: required_tag(msg, Tag::MsgParamSubPlan)?,
+                recipient_id: required_tag(msg, Tag::MsgParamRecipientId)?,
+                recipient_login: required_tag(msg, Tag::MsgParamRecipientUserName)?,
+                recipient_display_name: tag(Tag::MsgParamRecipientDisplayName).unwrap_or_default(),
+                months: number_tag(msg, Tag::MsgParamMonths)?,
+                gift_months: number_tag(msg, Tag::MsgParamGiftMonths)?,
+                sender_count: number_tag(msg, Tag::MsgParamSenderCount)?,
+            },
+            "submysterygift" | "anonsubmysterygift" => EventKind::MysteryGift {
+                plan: required_tag(msg, Tag::MsgParamSubPlan)?,
+                count: number_tag(msg, Tag::MsgParamMassGiftCount)?
+                    .context("Missing gift count")?,
+                sender_count: number_tag(msg, Tag::MsgParamSenderCount)?,
+            },
+            "giftpaidupgrade" | "anongiftpaidupgrade" => EventKind::GiftUpgrade {
+                sender_login: tag(Tag::MsgParamSenderLogin),
+            },
+            "primepaidupgrade" => EventKind::PrimeUpgrade {
+                plan: required_tag(msg, Tag::MsgParamSubPlan)?,
+            },
+            "raid" => EventKind::Raid {
+                viewer_count: number_tag(msg, Tag::MsgParamViewerCount)?
+                    .context("Missing viewer count")?,
+            },
+            "announcement" => EventKind::Announcement {
+                color: tag(Tag::MsgParamColor),
+            },
+            "bitsbadgetier" => EventKind::BitsBadgeTier {
+                threshold: number_tag(msg, Tag::MsgParamThreshold)?.context("Missing threshold")?,
+            },
+            _ => EventKind::Other {
+                msg_id: msg_id.to_owned(),
+            },
+        };
+
+        let message = Some(msg.user_text())
+            .filter(|text| !text.is_empty())
+            .map(str::to_owned);
+
+        Ok(Self {
+            timestamp: DateTime::from_timestamp_millis(msg.timestamp.try_into()?)
+                .context("Invalid timestamp")?,
+            id: msg.id(),
+            user_id: msg.user_id.to_string(),
+            user_login: msg.user_login.to_string(),
+            display_name: msg.display_name().to_owned(),
+            message,
+            kind,
+        })
+    }
+
+    /// Users who are part of the event, the gift recipient in addition to the sender
+    pub fn user_ids(&self) -> impl Iterator<Item = &str> {
+        let recipient_id = match &self.kind {
+            EventKind::SubGift { recipient_id, .. } => Some(recipient_id.as_str()),
+            _ => None,
+        };
+        std::iter::once(self.user_id.as_str()).chain(recipient_id)
+    }
+}
+
+impl EventKind {
+    pub fn name(&self) -> &'static str {
+        self.into()
+    }
+}
+
+/// Aggregated counts of a list of events
+#[derive(Serialize, JsonSchema, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelEventCounts {
+    /// Amount of events of each type
+    pub by_type: BTreeMap<&'static str, u64>,
+    /// Subs gifted to individual users. Mystery gifts are announced as individual gifts as well,
+    /// so they are not counted again
+    pub gifted_subs: u64,
+    /// Viewers brought in by all raids
+    pub raid_viewers: u64,
+}
+
+impl ChannelEventCounts {
+    pub fn new(events: &[ChannelEvent]) -> Self {
+        let mut counts = Self::default();
+
+        for event in events {
+            *counts.by_type.entry(event.kind.name()).or_default() += 1;
+
+            match &event.kind {
+                EventKind::SubGift { .. } => counts.gifted_subs += 1,
+                EventKind::Raid { viewer_count } => counts.raid_viewers += *viewer_count as u64,
+                _ => (),
+            }
+        }
+
+        counts
+    }
+}
+
+fn required_tag(msg: &StructuredMessage, tag: Tag) -> anyhow::Result<String> {
+    msg.extra_tag(tag.clone())
+        .map(str::to_owned)
+        .with_context(|| format!("Missing {}", tag.as_str()))
+}
+
+fn number_tag<T: FromStr>(msg: &StructuredMessage, tag: Tag) -> anyhow::Result<Option<T>> {
+    msg.extra_tag(tag.clone())
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid {}: {value}", tag.as_str()))
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChannelEvent, EventKind, Subscription};
+    use crate::db::schema::{StructuredMessage, UnstructuredMessage};
+    use pretty_assertions::assert_eq;
+
+    fn parse(channel_id: &str, user_id: &str, raw: &str) -> ChannelEvent {
+        let unstructured = UnstructuredMessage {
+            channel_id,
+            user_id,
+            timestamp: 1686947117960,
+            raw,
+        };
+        let structured = StructuredMessage::from_unstructured(&unstructured).unwrap();
+        ChannelEvent::from_structured(&structured).unwrap().unwrap()
+    }
+
+    #[test]
+    fn parse_resub() {
+        let event = parse(
+            "22484632",
+            "444158477",
+            r"@mod=0;id=0a4b7b50-052e-473e-99ee-441f05ce52a7;login=daney___;msg-param-multimonth-duration=0;display-name=daney___;msg-param-sub-plan-name=Channel\sSubscription\s(forsenlol);msg-param-was-gifted=false;subscriber=1;msg-param-cumulative-months=19;flags=;color=#8A2BE2;msg-param-months=0;user-id=444158477;badges=subscriber/12;user-type=;msg-param-should-share-streak=0;msg-id=resub;emotes=;msg-param-sub-plan=1000;room-id=22484632;system-msg=daney___\ssubscribed\sat\sTier\s1.\sThey've\ssubscribed\sfor\s19\smonths!;tmi-sent-ts=1686947117960;msg-param-multimonth-tenure=0;badge-info=subscriber/19 :tmi.twitch.tv USERNOTICE #forsen :Still here? LULE",
+        );
+
+        assert_eq!("daney___", event.user_login);
+        assert_eq!(Some("Still here? LULE"), event.message.as_deref());
+        assert_eq!(
+            EventKind::Resub(Subscription {
+                plan: "1000".to_owned(),
+                plan_name: Some("Channel Subscription (forsenlol)".to_owned()),
+                cumulative_months: Some(19),
+                streak_months: None,
+                multimonth_duration: Some(0),
+                was_gifted: false,
+            }),
+            event.kind
+        );
+    }
+
+    #[test]
+    fn parse_sub_gift() {
+        let event = parse(
+            "22484632",
+            "123456",
+            r"@badge-info=;badges=;color=;display-name=Gifter;emotes=;flags=;id=d8f4a1b6-7a3c-4a53-9d59-2c5a1b8c9e10;login=gifter;mod=0;msg-id=subgift;msg-param-gift-months=1;msg-param-months=3;msg-param-origin-id=1234;msg-param-recipient-display-name=Recipient;msg-param-recipient-id=654321;msg-param-recipient-user-name=recipient;msg-param-sender-count=5;msg-param-sub-plan-name=Channel\sSubscription;msg-param-sub-plan=1000;room-id=22484632;subscriber=0;system-msg=Gifter\sgifted\sa\sTier\s1\ssub\sto\sRecipient!;tmi-sent-ts=1686947117960;user-id=123456;user-type= :tmi.twitch.tv USERNOTICE #forsen",
+        );
+
+        assert_eq!(None, event.message);
+        assert_eq!(
+            EventKind::SubGift {
+                plan: "1000".to_owned(),
+                recipient_id: "654321".to_owned(),
+                recipient_login: "recipient".to_owned(),
+                recipient_display_name: "Recipient".to_owned(),
+                months: Some(3),
+                gift_months: Some(1),
+                sender_count: Some(5),
+            },
+            event.kind
+        );
+        assert_eq!(
+            vec!["123456", "654321"],
+            event.user_ids().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn parse_raid() {
+        let event = parse(
+            "22484632",
+            "789",
+            r"@badge-info=;badges=;color=#FF0000;display-name=Raider;emotes=;flags=;id=3d1f9b2e-6c7a-4e8b-9f0a-1b2c3d4e5f60;login=raider;mod=0;msg-id=raid;msg-param-displayName=Raider;msg-param-login=raider;msg-param-viewerCount=1234;room-id=22484632;subscriber=0;system-msg=1234\sraiders\sfrom\sRaider\shave\sjoined!;tmi-sent-ts=1686947117960;user-id=789;user-type= :tmi.twitch.tv USERNOTICE #forsen",
+        );
+
+        assert_eq!(EventKind::Raid { viewer_count: 1234 }, event.kind);
+    }
+
+    #[test]
+    fn reject_invalid_raid() {
+        let unstructured = UnstructuredMessage {
+            channel_id: "22484632",
+            user_id: "789",
+            timestamp: 1686947117960,
+            raw: r"@display-name=Raider;login=raider;msg-id=raid;msg-param-viewerCount=many;room-id=22484632;tmi-sent-ts=1686947117960;user-id=789 :tmi.twitch.tv USERNOTICE #forsen",
+        };
+        let structured = StructuredMessage::from_unstructured(&unstructured).unwrap();
+        assert!(ChannelEvent::from_structured(&structured).unwrap().is_err());
+    }
+}
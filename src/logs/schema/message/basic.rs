@@ -16,6 +16,8 @@ pub struct BasicMessage<'a> {
     pub timestamp: DateTime<Utc>,
     pub id: Cow<'a, str>,
     pub tags: HashMap<&'a str, Cow<'a, str>>,
+    /// Whether the message was removed by a moderator, either directly or by a ban or timeout
+    pub deleted: bool,
 }
 
 impl<'a> ResponseMessage<'a> for BasicMessage<'a> {
@@ -31,6 +33,7 @@ impl<'a> ResponseMessage<'a> for BasicMessage<'a> {
                 .into_iter()
                 .map(|(tag, value)| (tag.as_str(), value))
                 .collect(),
+            deleted: msg.deleted,
         })
     }
 }
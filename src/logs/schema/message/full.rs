@@ -74,6 +74,7 @@ mod tests {
                 .into_iter()
                 .map(|(k, v)| (k, Cow::Borrowed(v)))
                 .collect(),
+                deleted: false,
             },
             raw: "@tmi-sent-ts=1489263601000;room-id=22484632;user-id=62541963;display-name=Snusbot;badges=;badge-info=;flags=;user-type=;emotes= :snusbot!snusbot@snusbot.tmi.twitch.tv PRIVMSG #forsen :prasoc won 10 points in roulette and now has 2838 points! forsenPls".to_owned(),
             r#type: MessageType::PrivMsg,
@@ -1,8 +1,15 @@
+pub mod event;
 pub mod message;
 
+use crate::db::schema::{MessageType, StructuredMessage};
 use chrono::{DateTime, Utc};
 use schemars::JsonSchema;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+/// How long before a ban or timeout a message has to be sent to count as removed by it
+pub const CLEARCHAT_WINDOW_MS: u64 = 60 * 60 * 1000;
 
 #[derive(Deserialize, JsonSchema, Clone, Copy, Debug)]
 pub struct LogRangeParams {
@@ -17,3 +24,104 @@ impl LogRangeParams {
         self.from.zip(self.to)
     }
 }
+
+/// A period of time in which messages were not being logged
+#[derive(Serialize, JsonSchema, Clone, Debug)]
+pub struct LogGap {
+    pub from: DateTime<Utc>,
+    /// Not set if logging has not resumed yet
+    pub to: Option<DateTime<Utc>>,
+    /// Set when the connection loss was only noticed afterwards.
+    /// `from` is then the last logged message, and logging may have continued for a while after it
+    pub uncertain_start: bool,
+}
+
+/// Messages which were removed by moderators, either directly or by banning or timing out their sender
+#[derive(Default, Debug)]
+pub struct Deletions {
+    /// Ids of messages deleted with CLEARMSG
+    pub message_ids: HashSet<Uuid>,
+    /// Timestamps of bans and timeouts by channel id and user id
+    pub clears: HashMap<String, HashMap<String, Vec<u64>>>,
+}
+
+impl Deletions {
+    pub fn is_deleted(&self, msg: &StructuredMessage) -> bool {
+        if msg.message_type != MessageType::PrivMsg {
+            return false;
+        }
+
+        msg.uuid().is_some_and(|id| self.message_ids.contains(&id))
+            || self
+                .clears
+                .get(msg.channel_id.as_ref())
+                .and_then(|users| users.get(msg.user_id.as_ref()))
+                .is_some_and(|timestamps| {
+                    timestamps.iter().any(|timestamp| {
+                        (msg.timestamp..msg.timestamp + CLEARCHAT_WINDOW_MS).contains(timestamp)
+                    })
+                })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Deletions, CLEARCHAT_WINDOW_MS};
+    use crate::db::schema::{StructuredMessage, UnstructuredMessage};
+    use std::collections::HashMap;
+    use uuid::Uuid;
+
+    const MESSAGE_ID: &str = "0a4b7b50-052e-473e-99ee-441f05ce52a7";
+    const SENT_AT: u64 = 1686947117960;
+
+    fn message(command: &str) -> StructuredMessage<'static> {
+        let raw = format!("@id={MESSAGE_ID};room-id=1;user-id=2;tmi-sent-ts={SENT_AT} :user!user@user.tmi.twitch.tv {command} #channel :hello");
+        let unstructured = UnstructuredMessage {
+            channel_id: "1",
+            user_id: "2",
+            timestamp: SENT_AT,
+            raw: &raw,
+        };
+        StructuredMessage::from_unstructured(&unstructured)
+            .unwrap()
+            .into_owned()
+    }
+
+    fn cleared_at(timestamp: u64) -> Deletions {
+        Deletions {
+            message_ids: Default::default(),
+            clears: HashMap::from([(
+                "1".to_owned(),
+                HashMap::from([("2".to_owned(), vec![timestamp])]),
+            )]),
+        }
+    }
+
+    #[test]
+    fn deleted_by_id() {
+        let deletions = Deletions {
+            message_ids: [Uuid::parse_str(MESSAGE_ID).unwrap()].into(),
+            clears: HashMap::new(),
+        };
+
+        assert!(deletions.is_deleted(&message("PRIVMSG")));
+        assert!(!Deletions::default().is_deleted(&message("PRIVMSG")));
+    }
+
+    #[test]
+    fn clear_window_boundaries() {
+        let msg = message("PRIVMSG");
+
+        assert!(!cleared_at(SENT_AT - 1).is_deleted(&msg));
+        assert!(cleared_at(SENT_AT).is_deleted(&msg));
+        assert!(cleared_at(SENT_AT + CLEARCHAT_WINDOW_MS - 1).is_deleted(&msg));
+        assert!(!cleared_at(SENT_AT + CLEARCHAT_WINDOW_MS).is_deleted(&msg));
+    }
+
+    #[test]
+    fn only_chat_messages_are_deleted() {
+        let msg = message("USERNOTICE");
+
+        assert!(!cleared_at(SENT_AT).is_deleted(&msg));
+    }
+}
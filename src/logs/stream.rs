@@ -1,11 +1,14 @@
 mod buffer_response;
 mod cursor;
+mod deletions;
 mod multi_query;
 
 pub use buffer_response::FlushBufferResponse;
 use cursor::CursorStream;
+use deletions::DeletionsStream;
 use multi_query::MultiQueryStream;
 
+use super::schema::Deletions;
 use crate::{db::schema::StructuredMessage, error::Error, Result};
 use clickhouse::query::RowCursor;
 use futures::{Stream, StreamExt};
@@ -19,6 +22,7 @@ pub enum LogsStream {
     Cursor(CursorStream),
     MultiQuery(MultiQueryStream),
     Provided(Option<Vec<StructuredMessage<'static>>>),
+    Deletions(DeletionsStream),
 }
 
 impl LogsStream {
@@ -39,6 +43,10 @@ impl LogsStream {
         }
     }
 
+    pub fn empty() -> Self {
+        Self::Provided(None)
+    }
+
     pub fn new_multi_query(
         cursors: Vec<RowCursor<StructuredMessage<'static>>>,
         buffer_response: FlushBufferResponse,
@@ -52,6 +60,10 @@ impl LogsStream {
             buffer_response,
         )))
     }
+
+    pub fn with_deletions(self, deletions: Deletions, hide_deleted: bool) -> Self {
+        Self::Deletions(DeletionsStream::new(self, deletions, hide_deleted))
+    }
 }
 
 impl Stream for LogsStream {
@@ -62,6 +74,7 @@ impl Stream for LogsStream {
             LogsStream::Cursor(stream) => stream.poll_next_unpin(cx),
             LogsStream::MultiQuery(stream) => stream.poll_next_unpin(cx),
             LogsStream::Provided(values) => Poll::Ready(values.take().map(Ok)),
+            LogsStream::Deletions(stream) => stream.poll_next_unpin(cx),
         }
     }
 }
@@ -25,6 +25,7 @@ impl FlushBufferResponse {
         user_id: Option<&str>,
         params: LogsParams,
         (from, to): (DateTime<Utc>, DateTime<Utc>),
+        excluded_user_ids: &[String],
     ) -> Self {
         let timestamp_range = (from.timestamp_millis() as u64)..(to.timestamp_millis() as u64);
 
@@ -38,6 +39,40 @@ impl FlushBufferResponse {
                 .await
         };
 
+        if !excluded_user_ids.is_empty() {
+            messages.retain(|msg| !excluded_user_ids.iter().any(|id| *id == msg.user_id));
+        }
+
+        Self::from_messages(messages, params)
+    }
+
+    /// Buffered messages of a user across multiple channels
+    pub async fn for_user(
+        buffer: &FlushBuffer,
+        channel_ids: &[String],
+        user_id: &str,
+        params: LogsParams,
+        (from, to): (DateTime<Utc>, DateTime<Utc>),
+    ) -> Self {
+        let timestamp_range = (from.timestamp_millis() as u64)..(to.timestamp_millis() as u64);
+
+        let mut messages = Vec::new();
+        for channel_id in channel_ids {
+            messages.extend(
+                buffer
+                    .messages_by_channel_and_user(timestamp_range.clone(), channel_id, user_id)
+                    .await,
+            );
+        }
+        messages.sort_by_key(|msg| msg.timestamp);
+
+        Self::from_messages(messages, params)
+    }
+
+    pub fn from_messages(
+        mut messages: Vec<StructuredMessage<'static>>,
+        params: LogsParams,
+    ) -> Self {
         if params.reverse {
             messages.reverse();
         }
@@ -0,0 +1,49 @@
+use super::LogsStream;
+use crate::{db::schema::StructuredMessage, logs::schema::Deletions, Result};
+use futures::{Stream, StreamExt};
+use std::{
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+/// Marks messages which were removed by moderators, or leaves them out entirely
+pub struct DeletionsStream {
+    inner: Box<LogsStream>,
+    deletions: Deletions,
+    hide_deleted: bool,
+}
+
+impl DeletionsStream {
+    pub fn new(inner: LogsStream, deletions: Deletions, hide_deleted: bool) -> Self {
+        Self {
+            inner: Box::new(inner),
+            deletions,
+            hide_deleted,
+        }
+    }
+}
+
+impl Stream for DeletionsStream {
+    type Item = Result<Vec<StructuredMessage<'static>>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match ready!(self.inner.poll_next_unpin(cx)) {
+                Some(Ok(mut chunk)) => {
+                    for msg in &mut chunk {
+                        msg.deleted = self.deletions.is_deleted(msg);
+                    }
+                    if self.hide_deleted {
+                        chunk.retain(|msg| !msg.deleted);
+                    }
+
+                    // Empty chunks would end up as empty entries in JSON responses
+                    if !chunk.is_empty() {
+                        return Poll::Ready(Some(Ok(chunk)));
+                    }
+                }
+                other => return Poll::Ready(other),
+            }
+        }
+    }
+}
@@ -6,6 +6,7 @@ mod db;
 mod error;
 mod logs;
 mod migrator;
+mod source;
 mod web;
 
 pub type Result<T> = std::result::Result<T, error::Error>;
@@ -14,12 +15,18 @@ pub type ShutdownRx = watch::Receiver<()>;
 use anyhow::{anyhow, Context};
 use app::App;
 use args::{Args, Command};
+use bot::TwitchSource;
 use clap::Parser;
-use config::Config;
-use db::{setup_db, writer::create_writer};
+use config::{Config, ConfigTokenStorage};
+use db::{
+    setup_db,
+    spool::Spool,
+    writer::{create_writer, BufferLimits, FlushTriggers},
+};
 use futures::{future::try_join_all, stream::FuturesUnordered, StreamExt};
 use migrator::Migrator;
 use mimalloc::MiMalloc;
+use source::{replay::ReplaySource, MessageSink, MessageSource};
 use std::{
     env,
     sync::Arc,
@@ -36,7 +43,7 @@ use twitch_api::{
     twitch_oauth2::{AppAccessToken, Scope},
     HelixClient,
 };
-use twitch_irc::login::StaticLoginCredentials;
+use twitch_irc::login::{RefreshingLoginCredentials, StaticLoginCredentials};
 
 use crate::app::cache::UsersCache;
 
@@ -79,7 +86,11 @@ async fn main() -> anyhow::Result<()> {
         .context("Could not run DB migrations")?;
 
     match args.subcommand {
-        None => run(config, db).await,
+        None => run(config, db, None).await,
+        Some(Command::Replay { file, speed }) => {
+            let source = ReplaySource::new(file, speed);
+            run(config, db, Some(Box::new(source))).await
+        }
         Some(Command::Migrate {
             source_dir,
             channel_id,
@@ -88,16 +99,32 @@ async fn main() -> anyhow::Result<()> {
     }
 }
 
-async fn run(config: Config, db: clickhouse::Client) -> anyhow::Result<()> {
+/// Logs messages from the given source, or from Twitch if none is specified
+async fn run(
+    config: Config,
+    db: clickhouse::Client,
+    source: Option<Box<dyn MessageSource>>,
+) -> anyhow::Result<()> {
     let mut shutdown_rx = listen_shutdown().await;
 
     let helix_client: HelixClient<reqwest::Client> = HelixClient::default();
     let token = generate_token(&config).await?;
 
+    let spool = config
+        .spool_path
+        .as_ref()
+        .map(|path| Spool::open(path, config.spool_max_size))
+        .transpose()
+        .context("Could not open spool")?;
+
+    let config = Arc::new(config);
     let (writer_tx, flush_buffer, mut writer_handle) = create_writer(
         db.clone(),
+        config.clone(),
         shutdown_rx.clone(),
-        config.clickhouse_flush_interval,
+        FlushTriggers::from_config(&config),
+        BufferLimits::from_config(&config),
+        spool,
     )
     .await?;
 
@@ -105,22 +132,35 @@ async fn run(config: Config, db: clickhouse::Client) -> anyhow::Result<()> {
         helix_client,
         token: Arc::new(token),
         users: UsersCache::default(),
-        config: Arc::new(config),
+        config,
         db: Arc::new(db),
         optout_codes: Arc::default(),
+        optin_codes: Arc::default(),
         flush_buffer,
     };
 
     let (bot_tx, bot_rx) = mpsc::channel(1);
 
-    let login_credentials = StaticLoginCredentials::anonymous();
-    let mut bot_handle = tokio::spawn(bot::run(
-        login_credentials,
-        app.clone(),
-        writer_tx,
-        shutdown_rx.clone(),
-        bot_rx,
-    ));
+    let source: Box<dyn MessageSource> = match source {
+        Some(source) => source,
+        None if app.config.bot_token.read().unwrap().is_some() => {
+            info!("Using authenticated bot login");
+            let login_credentials = RefreshingLoginCredentials::init_with_username(
+                app.config.bot_login.clone(),
+                app.config.client_id.clone(),
+                app.config.client_secret.clone(),
+                ConfigTokenStorage::new(app.config.clone()),
+            );
+            Box::new(TwitchSource::new(login_credentials, bot_rx))
+        }
+        None => {
+            let login_credentials = StaticLoginCredentials::anonymous();
+            Box::new(TwitchSource::new(login_credentials, bot_rx))
+        }
+    };
+    let sink = MessageSink::new(app.clone(), writer_tx)?;
+
+    let mut bot_handle = tokio::spawn(source.run(sink, shutdown_rx.clone()));
     let mut web_handle = tokio::spawn(web::run(app, shutdown_rx.clone(), bot_tx));
 
     tokio::select! {
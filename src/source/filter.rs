@@ -0,0 +1,140 @@
+use crate::{config::Config, db::schema::MessageType};
+use anyhow::Context;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
+
+lazy_static! {
+    static ref MESSAGES_FILTERED_COUNTERS: IntCounterVec = register_int_counter_vec!(
+        "rustlog_messages_filtered",
+        "How many messages were not logged because of their type",
+        &["channel_id", "message_type"]
+    )
+    .unwrap();
+}
+
+/// Decides which message types are logged, based on the global and per-channel config
+#[derive(Clone)]
+pub struct MessageTypeFilter {
+    /// All types are logged if not set
+    global: Option<HashSet<MessageType>>,
+    channels: HashMap<String, HashSet<MessageType>>,
+}
+
+impl MessageTypeFilter {
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        let global = config
+            .message_types
+            .as_deref()
+            .map(parse_types)
+            .transpose()
+            .context("Invalid messageTypes")?;
+
+        let channels = config
+            .channel_message_types
+            .iter()
+            .map(|(channel_id, types)| {
+                let types = parse_types(types)
+                    .with_context(|| format!("Invalid channelMessageTypes for {channel_id}"))?;
+                Ok((channel_id.clone(), types))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self { global, channels })
+    }
+
+    /// Returns whether the message should be logged, counting it if it was filtered
+    pub fn check(&self, channel_id: &str, message_type: MessageType) -> bool {
+        let allowed = match self.channels.get(channel_id).or(self.global.as_ref()) {
+            Some(types) => types.contains(&message_type),
+            None => true,
+        };
+
+        if !allowed {
+            MESSAGES_FILTERED_COUNTERS
+                .with_label_values(&[channel_id, &message_type.to_string()])
+                .inc();
+        }
+
+        allowed
+    }
+}
+
+fn parse_types(names: &[String]) -> anyhow::Result<HashSet<MessageType>> {
+    names
+        .iter()
+        .map(|name| {
+            MessageType::from_str(&name.to_uppercase())
+                .with_context(|| format!("Unknown message type {name}"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MessageTypeFilter;
+    use crate::{config::Config, db::schema::MessageType};
+    use serde_json::json;
+
+    fn config(
+        message_types: serde_json::Value,
+        channel_message_types: serde_json::Value,
+    ) -> Config {
+        serde_json::from_value(json!({
+            "clickhouseUrl": "http://localhost:8123",
+            "clickhouseDb": "rustlog",
+            "channels": [],
+            "clientID": "",
+            "clientSecret": "",
+            "admins": [],
+            "messageTypes": message_types,
+            "channelMessageTypes": channel_message_types,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn allows_everything_by_default() {
+        let filter = MessageTypeFilter::from_config(&config(json!(null), json!({}))).unwrap();
+
+        assert!(filter.check("1", MessageType::PrivMsg));
+        assert!(filter.check("1", MessageType::Join));
+    }
+
+    #[test]
+    fn parses_case_insensitive_names() {
+        let filter =
+            MessageTypeFilter::from_config(&config(json!(["privmsg", "ClearChat"]), json!({})))
+                .unwrap();
+
+        assert!(filter.check("1", MessageType::PrivMsg));
+        assert!(filter.check("1", MessageType::ClearChat));
+        assert!(!filter.check("1", MessageType::UserNotice));
+    }
+
+    #[test]
+    fn channel_types_override_global() {
+        let filter = MessageTypeFilter::from_config(&config(
+            json!(["PRIVMSG"]),
+            json!({ "2": ["USERNOTICE"] }),
+        ))
+        .unwrap();
+
+        assert!(filter.check("1", MessageType::PrivMsg));
+        assert!(!filter.check("2", MessageType::PrivMsg));
+        assert!(filter.check("2", MessageType::UserNotice));
+    }
+
+    #[test]
+    fn rejects_unknown_types() {
+        assert!(
+            MessageTypeFilter::from_config(&config(json!(["PRIVMSG", "NOPE"]), json!({}))).is_err()
+        );
+        assert!(
+            MessageTypeFilter::from_config(&config(json!(null), json!({ "1": ["NOPE"] }))).is_err()
+        );
+    }
+}
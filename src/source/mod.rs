@@ -0,0 +1,85 @@
+pub mod filter;
+pub mod replay;
+
+use self::filter::MessageTypeFilter;
+use crate::{
+    app::App,
+    db::schema::{StructuredMessage, UnstructuredMessage},
+    ShutdownRx,
+};
+use async_trait::async_trait;
+use tokio::sync::mpsc::Sender;
+use tracing::error;
+
+/// Something that produces raw messages to be logged
+#[async_trait]
+pub trait MessageSource: Send {
+    /// Feeds messages into the sink until the shutdown signal is received
+    async fn run(self: Box<Self>, sink: MessageSink, shutdown_rx: ShutdownRx);
+}
+
+/// Converts messages from a source and passes them to the database writer
+#[derive(Clone)]
+pub struct MessageSink {
+    app: App,
+    writer_tx: Sender<StructuredMessage<'static>>,
+    type_filter: MessageTypeFilter,
+}
+
+impl MessageSink {
+    pub fn new(app: App, writer_tx: Sender<StructuredMessage<'static>>) -> anyhow::Result<Self> {
+        let type_filter = MessageTypeFilter::from_config(&app.config)?;
+        Ok(Self {
+            app,
+            writer_tx,
+            type_filter,
+        })
+    }
+
+    pub fn app(&self) -> &App {
+        &self.app
+    }
+
+    /// Converts a raw message into the form it is logged as.
+    /// Returns `None` if the message should not be logged.
+    pub fn structure(&self, message: &UnstructuredMessage) -> Option<StructuredMessage<'static>> {
+        let config = &self.app.config;
+        if config.opt_out.contains_key(message.user_id)
+            || config.paused_channels.contains(message.channel_id)
+            || (!config.store_ignored_users
+                && config.is_user_ignored(message.channel_id, message.user_id))
+        {
+            return None;
+        }
+
+        match StructuredMessage::from_unstructured(message) {
+            Ok(msg) => self
+                .type_filter
+                .check(message.channel_id, msg.message_type)
+                .then(|| msg.into_owned()),
+            Err(err) => {
+                error!("Could not convert message {message:?} to be logged: {err}");
+                None
+            }
+        }
+    }
+
+    /// Returns whether the message was accepted to be logged
+    pub async fn write(&self, message: &UnstructuredMessage<'_>) -> anyhow::Result<bool> {
+        match self.structure(message) {
+            Some(msg) => {
+                self.write_structured(msg).await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub async fn write_structured(
+        &self,
+        message: StructuredMessage<'static>,
+    ) -> anyhow::Result<()> {
+        self.writer_tx.send(message).await?;
+        Ok(())
+    }
+}
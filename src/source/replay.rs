@@ -0,0 +1,171 @@
+use super::{MessageSink, MessageSource};
+use crate::{
+    db::schema::UnstructuredMessage,
+    logs::extract::{extract_channel_and_user_from_raw, extract_raw_timestamp},
+    ShutdownRx,
+};
+use async_trait::async_trait;
+use chrono::Utc;
+use std::{path::PathBuf, time::Duration};
+use tmi::IrcMessageRef;
+use tokio::{
+    fs::File,
+    io::{self, AsyncBufRead, AsyncBufReadExt, BufReader},
+    time::sleep,
+};
+use tracing::{debug, error, info, warn};
+
+/// Reads raw IRC lines from a file or stdin, keeping their original timestamps
+pub struct ReplaySource {
+    /// Reads from stdin if not set
+    path: Option<PathBuf>,
+    /// How many times faster than real time messages are replayed. 0 means no delay at all
+    speed: f64,
+}
+
+impl ReplaySource {
+    pub fn new(path: Option<PathBuf>, speed: f64) -> Self {
+        Self { path, speed }
+    }
+
+    async fn replay(
+        &self,
+        sink: &MessageSink,
+        reader: impl AsyncBufRead + Unpin,
+    ) -> io::Result<()> {
+        let mut lines = reader.lines();
+        let mut previous_timestamp = None;
+        let (mut read_count, mut written_count) = (0, 0);
+
+        while let Some(line) = lines.next_line().await? {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            read_count += 1;
+
+            let Some(irc_message) = parse_line(line) else {
+                continue;
+            };
+            let Some((channel_id, maybe_user_id)) = extract_channel_and_user_from_raw(&irc_message)
+            else {
+                continue;
+            };
+            let timestamp = extract_raw_timestamp(&irc_message)
+                .unwrap_or_else(|| Utc::now().timestamp_millis().try_into().unwrap());
+
+            if self.speed > 0.0 {
+                if let Some(previous_timestamp) = previous_timestamp {
+                    let delay = timestamp.saturating_sub(previous_timestamp) as f64 / self.speed;
+                    sleep(Duration::from_millis(delay as u64)).await;
+                }
+                previous_timestamp = Some(timestamp);
+            }
+
+            let unstructured = UnstructuredMessage {
+                channel_id,
+                user_id: maybe_user_id.unwrap_or_default(),
+                timestamp,
+                raw: line,
+            };
+            match sink.write(&unstructured).await {
+                Ok(true) => written_count += 1,
+                Ok(false) => (),
+                Err(err) => {
+                    error!("Could not write replayed message: {err}");
+                    break;
+                }
+            }
+        }
+
+        info!("Replay finished, {written_count} out of {read_count} lines were logged");
+        Ok(())
+    }
+}
+
+/// Returns `None` for lines which can't be logged, either because they are malformed
+/// or because they don't belong to a channel
+fn parse_line(line: &str) -> Option<IrcMessageRef<'_>> {
+    let Some(irc_message) = IrcMessageRef::parse(line) else {
+        warn!("Could not parse line {line}");
+        return None;
+    };
+    extract_channel_and_user_from_raw(&irc_message)?;
+    Some(irc_message)
+}
+
+#[async_trait]
+impl MessageSource for ReplaySource {
+    async fn run(self: Box<Self>, sink: MessageSink, mut shutdown_rx: ShutdownRx) {
+        let result = async {
+            match &self.path {
+                Some(path) => {
+                    info!("Replaying messages from {}", path.display());
+                    let file = File::open(path).await?;
+                    self.replay(&sink, BufReader::new(file)).await
+                }
+                None => {
+                    info!("Replaying messages from stdin");
+                    self.replay(&sink, BufReader::new(io::stdin())).await
+                }
+            }
+        };
+
+        tokio::select! {
+            result = result => {
+                if let Err(err) = result {
+                    error!("Could not read replay input: {err}");
+                }
+            }
+            _ = shutdown_rx.changed() => {
+                debug!("Shutting down replay source");
+                return;
+            }
+        }
+
+        // Keep running so the logs can still be viewed after the replay is done
+        shutdown_rx.changed().await.ok();
+        debug!("Shutting down replay source");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_line;
+    use crate::logs::extract::{extract_channel_and_user_from_raw, extract_raw_timestamp};
+
+    #[test]
+    fn parses_channel_message() {
+        let msg = parse_line("@room-id=22484632;user-id=68136884;tmi-sent-ts=1709251274940 :supibot!supibot@supibot.tmi.twitch.tv PRIVMSG #forsen :hello").unwrap();
+
+        assert_eq!(
+            extract_channel_and_user_from_raw(&msg),
+            Some(("22484632", Some("68136884")))
+        );
+        assert_eq!(extract_raw_timestamp(&msg), Some(1709251274940));
+    }
+
+    #[test]
+    fn skips_malformed_lines() {
+        assert!(parse_line("@room-id=22484632").is_none());
+        assert!(parse_line(":").is_none());
+        assert!(parse_line("@ PRIVMSG").is_none());
+    }
+
+    #[test]
+    fn skips_lines_without_channel() {
+        assert!(parse_line("PING :tmi.twitch.tv").is_none());
+        assert!(
+            parse_line(":supibot!supibot@supibot.tmi.twitch.tv PRIVMSG #forsen :hello").is_none()
+        );
+    }
+
+    #[test]
+    fn invalid_timestamp_is_ignored() {
+        let msg =
+            parse_line("@room-id=22484632;tmi-sent-ts=yesterday :tmi.twitch.tv ROOMSTATE #forsen")
+                .unwrap();
+
+        assert_eq!(extract_raw_timestamp(&msg), None);
+    }
+}
@@ -1,4 +1,10 @@
-use crate::{app::App, bot::BotMessage, error::Error};
+use super::schema::{OptOutChange, UserDeletionStatus, UserIdPath};
+use crate::{
+    app::App,
+    bot::BotMessage,
+    db::{self, schema::OptOutEventType},
+    error::Error,
+};
 use aide::{
     openapi::{
         HeaderStyle, Parameter, ParameterData, ParameterSchemaOrContent, ReferenceOr, SchemaObject,
@@ -6,11 +12,12 @@ use aide::{
     transform::TransformOperation,
 };
 use axum::{
-    extract::{Request, State},
+    extract::{Path, Request, State},
     middleware::Next,
     response::{IntoResponse, Response},
     Extension, Json,
 };
+use chrono::DateTime;
 use reqwest::StatusCode;
 use schemars::JsonSchema;
 use serde::Deserialize;
@@ -75,7 +82,10 @@ pub async fn add_channels(
     let users = app.get_users(channels, vec![], false).await?;
     let names = users.into_values().collect();
 
-    bot_tx.send(BotMessage::JoinChannels(names)).await.unwrap();
+    bot_tx
+        .send(BotMessage::JoinChannels(names))
+        .await
+        .map_err(|_| Error::Internal)?;
 
     Ok(())
 }
@@ -88,7 +98,56 @@ pub async fn remove_channels(
     let users = app.get_users(channels, vec![], false).await?;
     let names = users.into_values().collect();
 
-    bot_tx.send(BotMessage::PartChannels(names)).await.unwrap();
+    bot_tx
+        .send(BotMessage::PartChannels(names))
+        .await
+        .map_err(|_| Error::Internal)?;
+
+    Ok(())
+}
+
+pub async fn get_user_deletion_status(
+    app: State<App>,
+    Path(UserIdPath { user_id }): Path<UserIdPath>,
+) -> Result<Json<UserDeletionStatus>, Error> {
+    let mutations = db::get_user_deletion_status(&app.db, &user_id).await?;
+    let history = db::read_opt_out_events(&app.db, &user_id)
+        .await?
+        .into_iter()
+        .map(|event| OptOutChange {
+            timestamp: DateTime::from_timestamp_millis(event.timestamp as i64)
+                .expect("Invalid DateTime"),
+            opted_out: event.event_type == OptOutEventType::OptOut,
+        })
+        .collect();
+
+    Ok(Json(UserDeletionStatus {
+        opted_out: app.config.opt_out.contains_key(&user_id),
+        is_done: !mutations.is_empty() && mutations.iter().all(|mutation| mutation.is_done),
+        user_id,
+        mutations,
+        history,
+    }))
+}
 
+pub async fn retry_user_deletion(
+    app: State<App>,
+    Path(UserIdPath { user_id }): Path<UserIdPath>,
+) -> Result<(), Error> {
+    if !app.config.opt_out.contains_key(&user_id) {
+        return Err(Error::NotFound);
+    }
+    app.delete_user_logs(&user_id).await?;
     Ok(())
 }
+
+pub async fn remove_optout(
+    app: State<App>,
+    Path(UserIdPath { user_id }): Path<UserIdPath>,
+) -> Result<(), Error> {
+    if app.optin_user(&user_id).await? {
+        Ok(())
+    } else {
+        Err(Error::NotFound)
+    }
+}
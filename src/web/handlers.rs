@@ -1,20 +1,30 @@
 use super::{
-    responders::logs::LogsResponse,
+    responders::logs::{LogsResponse, LogsResponseType},
     schema::{
-        AvailableLogs, AvailableLogsParams, Channel, ChannelIdType, ChannelLogsByDatePath,
-        ChannelLogsStats, ChannelParam, ChannelsList, LogsParams, LogsPathChannel, SearchParams,
-        UserIdType, UserLogPathParams, UserLogsDatePath, UserLogsStats, UserNameHistoryParam,
-        UserParam,
+        ActivityBucket, AvailableLogs, AvailableLogsParams, Channel, ChannelActivity,
+        ChannelActivityParams, ChannelEventsList, ChannelEventsParams, ChannelIdType,
+        ChannelLogsByDatePath, ChannelLogsStats, ChannelParam, ChannelsList, Leaderboard,
+        LeaderboardEntry, LeaderboardParams, LogsParams, LogsPathChannel, MessageContextParams,
+        MessagePath, ModerationAction, ModerationActionType, ModerationActionsList, SearchParams,
+        UserChannel, UserChannelsList, UserIdType, UserLogPathParams, UserLogsDatePath,
+        UserLogsStats, UserNameHistoryParam, UserParam, UserPath,
     },
 };
 use crate::{
     app::App,
     db::{
-        self, read_available_channel_logs, read_available_user_logs, read_channel,
-        read_random_channel_line, read_random_user_line, read_user,
+        self, read_available_channel_logs, read_available_user_logs, read_channel, read_log_gaps,
+        read_random_channel_line, read_random_user_line, read_user, schema::MessageType,
+        ModerationActionRow,
     },
     error::Error,
-    logs::{schema::LogRangeParams, stream::LogsStream},
+    logs::{
+        schema::{
+            event::{ChannelEvent, ChannelEventCounts},
+            LogRangeParams,
+        },
+        stream::LogsStream,
+    },
     web::schema::LogsPathDate,
     Result,
 };
@@ -26,9 +36,26 @@ use axum::{
 };
 use axum_extra::{headers::CacheControl, TypedHeader};
 use chrono::{DateTime, Days, Months, NaiveDate, NaiveTime, Utc};
+use dashmap::DashSet;
 use rand::{distr::Alphanumeric, rng, Rng};
-use std::time::Duration;
-use tracing::debug;
+use std::{collections::HashSet, str::FromStr, sync::Arc, time::Duration};
+use tracing::{debug, error};
+use uuid::Uuid;
+
+/// Total amount of results when the response only contains part of them
+const TOTAL_COUNT_HEADER: &str = "X-Total-Count";
+/// Range of the cross-channel user logs if none is specified
+const USER_LOGS_DEFAULT_DAYS: u64 = 30;
+const MAX_MESSAGE_CONTEXT: u64 = 100;
+/// Range of the channel moderation history if none is specified
+const MODERATION_DEFAULT_DAYS: u64 = 30;
+/// Range of the channel events if none is specified
+const EVENTS_DEFAULT_DAYS: u64 = 30;
+/// Range of the channel activity stats if none is specified
+const ACTIVITY_DEFAULT_DAYS: u64 = 7;
+const MAX_ACTIVITY_BUCKETS: u64 = 10_000;
+const DEFAULT_LEADERBOARD_SIZE: u64 = 10;
+const MAX_LEADERBOARD_SIZE: u64 = 1000;
 
 pub async fn get_channels(app: State<App>) -> impl IntoApiResponse {
     let channel_ids = app.config.channels.read().unwrap().clone();
@@ -91,8 +118,13 @@ pub async fn get_channel_stats(
         ChannelIdType::Name => app.get_user_id_by_name(&channel).await?,
         ChannelIdType::Id => channel.clone(),
     };
-    let (message_count, stats_rows) =
-        db::get_channel_stats(&app.db, &channel_id, range_params).await?;
+    let (message_count, stats_rows) = db::get_channel_stats(
+        &app.db,
+        &channel_id,
+        range_params,
+        &app.config.ignored_users_in(&channel_id),
+    )
+    .await?;
 
     let user_ids = stats_rows.iter().map(|row| row.user_id.clone()).collect();
     let mut users = app.get_users(user_ids, vec![], false).await?;
@@ -112,6 +144,138 @@ pub async fn get_channel_stats(
     }))
 }
 
+pub async fn get_channel_leaderboard(
+    app: State<App>,
+    Path(LogsPathChannel {
+        channel_id_type,
+        channel,
+    }): Path<LogsPathChannel>,
+    Query(range_params): Query<LogRangeParams>,
+    Query(params): Query<LeaderboardParams>,
+) -> Result<impl IntoApiResponse> {
+    let channel_id = match channel_id_type {
+        ChannelIdType::Name => app.get_user_id_by_name(&channel).await?,
+        ChannelIdType::Id => channel,
+    };
+
+    app.check_opted_out(&channel_id, None)?;
+
+    let limit = params.limit.unwrap_or(DEFAULT_LEADERBOARD_SIZE);
+    if limit > MAX_LEADERBOARD_SIZE {
+        return Err(Error::InvalidParam(format!(
+            "Limit can be at most {MAX_LEADERBOARD_SIZE}"
+        )));
+    }
+
+    let message_type = match &params.message_type {
+        Some(value) => MessageType::from_str(&value.to_uppercase())
+            .map_err(|_| Error::InvalidParam(format!("Unknown message type {value}")))?,
+        None => MessageType::PrivMsg,
+    };
+
+    let (total_count, rows) = db::get_channel_leaderboard(
+        &app.db,
+        &channel_id,
+        range_params,
+        &params,
+        message_type,
+        limit,
+        &app.config.ignored_users_in(&channel_id),
+    )
+    .await?;
+
+    let user_ids = rows.iter().map(|row| row.user_id.clone()).collect();
+    let mut users = app.get_users(user_ids, vec![], false).await?;
+
+    let offset = params.offset.unwrap_or(0);
+    let chatters = rows
+        .into_iter()
+        .zip(offset + 1..)
+        .map(|(row, rank)| LeaderboardEntry {
+            rank,
+            user_login: users.remove(&row.user_id),
+            user_id: row.user_id,
+            message_count: row.message_count,
+            active_days: row.active_days,
+        })
+        .collect();
+
+    Ok((
+        no_cache_header(),
+        [(TOTAL_COUNT_HEADER, total_count.to_string())],
+        Json(Leaderboard { chatters }),
+    ))
+}
+
+pub async fn get_channel_activity(
+    app: State<App>,
+    Path(LogsPathChannel {
+        channel_id_type,
+        channel,
+    }): Path<LogsPathChannel>,
+    Query(range_params): Query<LogRangeParams>,
+    Query(ChannelActivityParams { bucket }): Query<ChannelActivityParams>,
+) -> Result<impl IntoApiResponse> {
+    let channel_id = match channel_id_type {
+        ChannelIdType::Name => app.get_user_id_by_name(&channel).await?,
+        ChannelIdType::Id => channel,
+    };
+
+    app.check_opted_out(&channel_id, None)?;
+
+    let range = range_params.range().unwrap_or_else(|| {
+        let now = Utc::now();
+        (now - Days::new(ACTIVITY_DEFAULT_DAYS), now)
+    });
+    let bucket_count = (range.1 - range.0).num_seconds().max(0) as u64 / bucket.seconds();
+    if bucket_count > MAX_ACTIVITY_BUCKETS {
+        return Err(Error::InvalidParam(format!(
+            "Range is too large for the bucket size, at most {MAX_ACTIVITY_BUCKETS} buckets can be returned"
+        )));
+    }
+
+    let buckets = db::get_channel_activity(&app.db, &app.flush_buffer, &channel_id, bucket, range)
+        .await?
+        .into_iter()
+        .map(|row| ActivityBucket {
+            timestamp: DateTime::from_timestamp(row.bucket.into(), 0).expect("Invalid DateTime"),
+            message_count: row.message_count,
+            unique_chatters: row.unique_chatters,
+            first_time_chatters: row.first_time_chatters,
+            sub_events: row.sub_events,
+            moderation_actions: row.moderation_actions,
+        })
+        .collect();
+
+    let cache = if Utc::now() < range.1 {
+        no_cache_header()
+    } else {
+        cache_header(36000)
+    };
+
+    Ok((cache, Json(ChannelActivity { bucket, buckets })))
+}
+
+pub async fn get_channel_gaps(
+    app: State<App>,
+    Path(LogsPathChannel {
+        channel_id_type,
+        channel,
+    }): Path<LogsPathChannel>,
+    Query(range_params): Query<LogRangeParams>,
+) -> Result<impl IntoApiResponse> {
+    let channel_id = match channel_id_type {
+        ChannelIdType::Name => app.get_user_id_by_name(&channel).await?,
+        ChannelIdType::Id => channel,
+    };
+
+    app.check_opted_out(&channel_id, None)?;
+
+    let gaps = read_log_gaps(&app.db, &channel_id, range_params.range()).await?;
+
+    Ok((no_cache_header(), Json(gaps)))
+}
+
 pub async fn get_user_stats(
     Path(user_params): Path<UserLogPathParams>,
     Query(range_params): Query<LogRangeParams>,
@@ -167,12 +331,27 @@ async fn get_channel_logs_inner(
 ) -> Result<impl IntoApiResponse> {
     app.check_opted_out(channel_id, None)?;
 
-    let stream = read_channel(&app.db, channel_id, params, &app.flush_buffer, range).await?;
-
-    let logs = LogsResponse {
-        response_type: params.response_type(),
-        stream,
+    let excluded_user_ids = if params.exclude_bots {
+        app.config.ignored_users_in(channel_id)
+    } else {
+        Vec::new()
     };
+    let stream = match read_channel(
+        &app.db,
+        channel_id,
+        params,
+        &app.flush_buffer,
+        range,
+        &excluded_user_ids,
+    )
+    .await
+    {
+        Ok(stream) => {
+            Ok(mark_deletions(app, stream, &[channel_id.to_owned()], None, params, range).await?)
+        }
+        Err(err) => Err(err),
+    };
+    let logs = logs_response(app, channel_id, stream, params, range).await?;
 
     let cache = if Utc::now() < range.1 {
         no_cache_header()
@@ -249,7 +428,8 @@ async fn get_user_logs_inner(
     logs_params: LogsParams,
     range: (DateTime<Utc>, DateTime<Utc>),
 ) -> Result<impl IntoApiResponse> {
-    let stream = read_user(
+    let range = since_opt_in(app, user_id, range).await?;
+    let stream = match read_user(
         &app.db,
         channel_id,
         user_id,
@@ -257,13 +437,129 @@ async fn get_user_logs_inner(
         &app.flush_buffer,
         range,
     )
-    .await?;
+    .await
+    {
+        Ok(stream) => Ok(mark_deletions(
+            app,
+            stream,
+            &[channel_id.to_owned()],
+            Some(user_id),
+            logs_params,
+            range,
+        )
+        .await?),
+        Err(err) => Err(err),
+    };
+    let logs = logs_response(app, channel_id, stream, logs_params, range).await?;
 
-    let logs = LogsResponse {
-        stream,
-        response_type: logs_params.response_type(),
+    let cache = if Utc::now() < range.1 {
+        no_cache_header()
+    } else {
+        cache_header(36000)
     };
 
+    Ok((cache, logs))
+}
+
+pub async fn get_user_channels(
+    app: State<App>,
+    Path(UserPath { user }): Path<UserPath>,
+) -> Result<impl IntoApiResponse> {
+    let user_id = app.get_user_id_by_name(&user).await?;
+    get_user_channels_inner(&app, &user_id).await
+}
+
+pub async fn get_user_id_channels(
+    app: State<App>,
+    Path(UserPath { user }): Path<UserPath>,
+) -> Result<impl IntoApiResponse> {
+    get_user_channels_inner(&app, &user).await
+}
+
+async fn get_user_channels_inner(app: &App, user_id: &str) -> Result<impl IntoApiResponse> {
+    app.check_user_opted_out(user_id)?;
+
+    let rows: Vec<_> = db::read_user_channels(&app.db, user_id)
+        .await?
+        .into_iter()
+        .filter(|row| app.check_opted_out(&row.channel_id, Some(user_id)).is_ok())
+        .collect();
+
+    let channel_ids = rows.iter().map(|row| row.channel_id.clone()).collect();
+    let mut logins = app.get_users(channel_ids, vec![], false).await?;
+
+    let channels = rows
+        .into_iter()
+        .map(|row| UserChannel {
+            channel_login: logins.remove(&row.channel_id),
+            channel_id: row.channel_id,
+            message_count: row.message_count,
+            first_timestamp: DateTime::from_timestamp_millis(row.first_timestamp)
+                .expect("Invalid DateTime"),
+            last_timestamp: DateTime::from_timestamp_millis(row.last_timestamp)
+                .expect("Invalid DateTime"),
+        })
+        .collect();
+
+    Ok((no_cache_header(), Json(UserChannelsList { channels })))
+}
+
+pub async fn get_user_logs_all_channels(
+    app: State<App>,
+    Path(UserPath { user }): Path<UserPath>,
+    Query(range_params): Query<LogRangeParams>,
+    Query(logs_params): Query<LogsParams>,
+) -> Result<impl IntoApiResponse> {
+    let user_id = app.get_user_id_by_name(&user).await?;
+    get_user_logs_all_channels_inner(&app, &user_id, range_params, logs_params).await
+}
+
+pub async fn get_user_id_logs_all_channels(
+    app: State<App>,
+    Path(UserPath { user }): Path<UserPath>,
+    Query(range_params): Query<LogRangeParams>,
+    Query(logs_params): Query<LogsParams>,
+) -> Result<impl IntoApiResponse> {
+    get_user_logs_all_channels_inner(&app, &user, range_params, logs_params).await
+}
+
+async fn get_user_logs_all_channels_inner(
+    app: &App,
+    user_id: &str,
+    range_params: LogRangeParams,
+    logs_params: LogsParams,
+) -> Result<impl IntoApiResponse> {
+    app.check_user_opted_out(user_id)?;
+
+    let channel_ids: Vec<String> = app
+        .config
+        .channels
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|channel_id| app.check_opted_out(channel_id, Some(user_id)).is_ok())
+        .cloned()
+        .collect();
+
+    let range = range_params.range().unwrap_or_else(|| {
+        let now = Utc::now();
+        (now - Days::new(USER_LOGS_DEFAULT_DAYS), now)
+    });
+    let range = since_opt_in(app, user_id, range).await?;
+
+    let stream = db::read_user_in_channels(
+        &app.db,
+        &channel_ids,
+        user_id,
+        logs_params,
+        &app.flush_buffer,
+        range,
+    )
+    .await?;
+    let stream =
+        mark_deletions(app, stream, &channel_ids, Some(user_id), logs_params, range).await?;
+    let logs = LogsResponse::new(stream, logs_params.response_type());
+
     let cache = if Utc::now() < range.1 {
         no_cache_header()
     } else {
@@ -317,10 +613,7 @@ pub async fn random_channel_line(
     let random_line = read_random_channel_line(&app.db, &channel_id).await?;
     let stream = LogsStream::new_provided(vec![random_line])?;
 
-    let logs = LogsResponse {
-        stream,
-        response_type: logs_params.response_type(),
-    };
+    let logs = LogsResponse::new(stream, logs_params.response_type());
     Ok((no_cache_header(), logs))
 }
 
@@ -336,10 +629,7 @@ pub async fn random_user_line(
     let random_line = read_random_user_line(&app.db, &channel_id, &user_id).await?;
     let stream = LogsStream::new_provided(vec![random_line])?;
 
-    let logs = LogsResponse {
-        stream,
-        response_type: logs_params.response_type(),
-    };
+    let logs = LogsResponse::new(stream, logs_params.response_type());
     Ok((no_cache_header(), logs))
 }
 
@@ -357,18 +647,239 @@ pub async fn search_user_logs(
         &app.db,
         &channel_id,
         &user_id,
-        &search_params.q,
+        &search_params.query().map_err(Error::InvalidParam)?,
         logs_params,
     )
     .await?;
 
-    let logs = LogsResponse {
-        stream,
-        response_type: logs_params.response_type(),
-    };
+    let logs = LogsResponse::new(stream, logs_params.response_type());
     Ok(logs)
 }
 
+pub async fn search_channel_logs(
+    app: State<App>,
+    Path(LogsPathChannel {
+        channel_id_type,
+        channel,
+    }): Path<LogsPathChannel>,
+    Query(search_params): Query<SearchParams>,
+    Query(range_params): Query<LogRangeParams>,
+    Query(logs_params): Query<LogsParams>,
+) -> Result<impl IntoApiResponse> {
+    let channel_id = match channel_id_type {
+        ChannelIdType::Name => app.get_user_id_by_name(&channel).await?,
+        ChannelIdType::Id => channel,
+    };
+
+    app.check_opted_out(&channel_id, None)?;
+
+    let excluded_user_ids = if logs_params.exclude_bots {
+        app.config.ignored_users_in(&channel_id)
+    } else {
+        Vec::new()
+    };
+    let (stream, total_count) = db::search_channel_logs(
+        &app.db,
+        &app.flush_buffer,
+        &channel_id,
+        &search_params.query().map_err(Error::InvalidParam)?,
+        range_params,
+        logs_params,
+        &excluded_user_ids,
+    )
+    .await?;
+
+    let logs = LogsResponse::new(stream, logs_params.response_type());
+    Ok((
+        no_cache_header(),
+        [(TOTAL_COUNT_HEADER, total_count.to_string())],
+        logs,
+    ))
+}
+
+pub async fn get_channel_moderation(
+    app: State<App>,
+    Path(LogsPathChannel {
+        channel_id_type,
+        channel,
+    }): Path<LogsPathChannel>,
+    Query(range_params): Query<LogRangeParams>,
+) -> Result<impl IntoApiResponse> {
+    let channel_id = match channel_id_type {
+        ChannelIdType::Name => app.get_user_id_by_name(&channel).await?,
+        ChannelIdType::Id => channel,
+    };
+
+    app.check_opted_out(&channel_id, None)?;
+
+    let range = range_params.range().unwrap_or_else(|| {
+        let now = Utc::now();
+        (now - Days::new(MODERATION_DEFAULT_DAYS), now)
+    });
+
+    let rows = db::read_moderation_actions(&app.db, &channel_id, None, Some(range)).await?;
+
+    // Deleted messages have no user id, so their sender's login is resolved to check it instead
+    let deleted_logins: Vec<String> = rows
+        .iter()
+        .filter(|row| row.message_type == MessageType::ClearMsg)
+        .map(|row| row.user_login.clone())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    let opted_out_logins: HashSet<String> = app
+        .get_users(vec![], deleted_logins, false)
+        .await?
+        .into_iter()
+        .filter(|(user_id, _)| app.config.opt_out.contains_key(user_id))
+        .map(|(_, login)| login)
+        .collect();
+
+    let actions = rows
+        .into_iter()
+        .filter(|row| {
+            !app.config.opt_out.contains_key(&row.user_id)
+                && !opted_out_logins.contains(&row.user_login)
+        })
+        .map(moderation_action)
+        .collect();
+
+    Ok((no_cache_header(), Json(ModerationActionsList { actions })))
+}
+
+pub async fn get_user_moderation(
+    app: State<App>,
+    Path(user_params): Path<UserLogPathParams>,
+    Query(range_params): Query<LogRangeParams>,
+) -> Result<impl IntoApiResponse> {
+    let (channel_id, user_id) = resolve_user_params(&user_params, &app).await?;
+
+    app.check_opted_out(&channel_id, Some(&user_id))?;
+
+    // Deletions are matched by login, so previous names are needed to find older ones
+    let user_logins = app.get_user_logins(&user_id).await?;
+
+    let actions = db::read_moderation_actions(
+        &app.db,
+        &channel_id,
+        Some((&user_id, &user_logins)),
+        range_params.range(),
+    )
+    .await?
+    .into_iter()
+    .map(moderation_action)
+    .collect();
+
+    Ok((no_cache_header(), Json(ModerationActionsList { actions })))
+}
+
+fn moderation_action(row: ModerationActionRow) -> ModerationAction {
+    let duration = row.ban_duration.parse().ok();
+    let action = match row.message_type {
+        MessageType::ClearMsg => ModerationActionType::Deletion,
+        _ if duration.is_some() => ModerationActionType::Timeout,
+        _ => ModerationActionType::Ban,
+    };
+    let is_deletion = action == ModerationActionType::Deletion;
+
+    ModerationAction {
+        timestamp: DateTime::from_timestamp_millis(row.timestamp).expect("Invalid DateTime"),
+        user_id: Some(row.user_id).filter(|user_id| !user_id.is_empty()),
+        user_login: row.user_login,
+        duration: duration.filter(|_| !is_deletion),
+        target_message_id: Some(row.target_msg_id).filter(|id| !id.is_empty()),
+        text: Some(row.text).filter(|_| is_deletion),
+        action,
+    }
+}
+
+pub async fn get_channel_events(
+    app: State<App>,
+    Path(LogsPathChannel {
+        channel_id_type,
+        channel,
+    }): Path<LogsPathChannel>,
+    Query(range_params): Query<LogRangeParams>,
+    Query(events_params): Query<ChannelEventsParams>,
+) -> Result<impl IntoApiResponse> {
+    let channel_id = match channel_id_type {
+        ChannelIdType::Name => app.get_user_id_by_name(&channel).await?,
+        ChannelIdType::Id => channel,
+    };
+
+    app.check_opted_out(&channel_id, None)?;
+
+    let range = range_params.range().unwrap_or_else(|| {
+        let now = Utc::now();
+        (now - Days::new(EVENTS_DEFAULT_DAYS), now)
+    });
+
+    let messages =
+        db::read_channel_user_notices(&app.db, &app.flush_buffer, &channel_id, range).await?;
+
+    let events: Vec<_> = messages
+        .iter()
+        .filter_map(|msg| match ChannelEvent::from_structured(msg)? {
+            Ok(event) => Some(event),
+            Err(err) => {
+                error!("Could not parse event {msg:?}: {err:#}");
+                None
+            }
+        })
+        .filter(|event| events_params.includes(event.kind.name()))
+        .filter(|event| {
+            event
+                .user_ids()
+                .all(|user_id| !app.config.opt_out.contains_key(user_id))
+        })
+        .collect();
+    let counts = ChannelEventCounts::new(&events);
+
+    let cache = if Utc::now() < range.1 {
+        no_cache_header()
+    } else {
+        cache_header(36000)
+    };
+
+    Ok((cache, Json(ChannelEventsList { events, counts })))
+}
+
+pub async fn get_message(
+    app: State<App>,
+    Path(MessagePath { id }): Path<MessagePath>,
+    Query(MessageContextParams { context }): Query<MessageContextParams>,
+    Query(logs_params): Query<LogsParams>,
+) -> Result<impl IntoApiResponse> {
+    let id =
+        Uuid::parse_str(&id).map_err(|_| Error::InvalidParam("Invalid message id".to_owned()))?;
+    let context = context.unwrap_or(0);
+    if context > MAX_MESSAGE_CONTEXT {
+        return Err(Error::InvalidParam(format!(
+            "Context can be at most {MAX_MESSAGE_CONTEXT} messages"
+        )));
+    }
+
+    let message = db::read_message(&app.db, &app.flush_buffer, id).await?;
+    app.check_opted_out(&message.channel_id, Some(&message.user_id))?;
+
+    let messages = if context > 0 {
+        let (before, after) =
+            db::read_message_context(&app.db, &app.flush_buffer, &message, context).await?;
+        before
+            .into_iter()
+            .chain(std::iter::once(message))
+            .chain(after)
+            .filter(|msg| !app.config.opt_out.contains_key(msg.user_id.as_ref()))
+            .collect()
+    } else {
+        vec![message]
+    };
+
+    let stream = LogsStream::new_provided(messages)?;
+    let logs = LogsResponse::new(stream, logs_params.response_type());
+    Ok((no_cache_header(), logs))
+}
+
 pub async fn get_user_name_history(
     app: State<App>,
     Path(UserNameHistoryParam { user_id }): Path<UserNameHistoryParam>,
@@ -381,23 +892,32 @@ pub async fn get_user_name_history(
 }
 
 pub async fn optout(app: State<App>) -> Json<String> {
+    Json(create_verification_code(&app.optout_codes))
+}
+
+pub async fn optin(app: State<App>) -> Json<String> {
+    Json(create_verification_code(&app.optin_codes))
+}
+
+/// Creates a code which has to be sent in chat to prove ownership of an account, valid for 60 seconds
+fn create_verification_code(codes: &Arc<DashSet<String>>) -> String {
     let mut rng = rng();
-    let optout_code: String = (0..5).map(|_| rng.sample(Alphanumeric) as char).collect();
+    let code: String = (0..5).map(|_| rng.sample(Alphanumeric) as char).collect();
 
-    app.optout_codes.insert(optout_code.clone());
+    codes.insert(code.clone());
 
     {
-        let codes = app.optout_codes.clone();
-        let optout_code = optout_code.clone();
+        let codes = codes.clone();
+        let code = code.clone();
         tokio::spawn(async move {
             tokio::time::sleep(Duration::from_secs(60)).await;
-            if codes.remove(&optout_code).is_some() {
-                debug!("Dropping optout code {optout_code}");
+            if codes.remove(&code).is_some() {
+                debug!("Dropping verification code {code}");
             }
         });
     }
 
-    Json(optout_code)
+    code
 }
 
 fn cache_header(secs: u64) -> TypedHeader<CacheControl> {
@@ -412,6 +932,59 @@ pub fn no_cache_header() -> TypedHeader<CacheControl> {
     TypedHeader(CacheControl::new().with_no_cache())
 }
 
+/// Adds the logging gaps of the range to JSON responses.
+/// Ranges without messages are still returned if there are gaps, as they can explain why
+async fn logs_response(
+    app: &App,
+    channel_id: &str,
+    stream: Result<LogsStream>,
+    params: LogsParams,
+    range: (DateTime<Utc>, DateTime<Utc>),
+) -> Result<LogsResponse> {
+    let response_type = params.response_type();
+    if !matches!(response_type, LogsResponseType::Json(_)) {
+        return Ok(LogsResponse::new(stream?, response_type));
+    }
+
+    let gaps = read_log_gaps(&app.db, channel_id, Some(range)).await?;
+    let stream = match stream {
+        Err(Error::NotFound) if !gaps.is_empty() => LogsStream::empty(),
+        other => other?,
+    };
+    Ok(LogsResponse::new(stream, response_type).with_gaps(gaps))
+}
+
+async fn mark_deletions(
+    app: &App,
+    stream: LogsStream,
+    channel_ids: &[String],
+    user_id: Option<&str>,
+    params: LogsParams,
+    range: (DateTime<Utc>, DateTime<Utc>),
+) -> Result<LogsStream> {
+    // Raw IRC lines have no deletion marker
+    if !params.hide_deleted && matches!(params.response_type(), LogsResponseType::Raw) {
+        return Ok(stream);
+    }
+
+    let deletions =
+        db::read_deletions(&app.db, &app.flush_buffer, channel_ids, user_id, range).await?;
+    Ok(stream.with_deletions(deletions, params.hide_deleted))
+}
+
+/// Starts the range at the user's latest opt in, so nothing from before their opt out
+/// is shown again if it was not fully deleted
+async fn since_opt_in(
+    app: &App,
+    user_id: &str,
+    (from, to): (DateTime<Utc>, DateTime<Utc>),
+) -> Result<(DateTime<Utc>, DateTime<Utc>)> {
+    match db::read_last_opt_in(&app.db, user_id).await? {
+        Some(opted_in_at) if opted_in_at > from => Ok((opted_in_at.min(to), to)),
+        _ => Ok((from, to)),
+    }
+}
+
 async fn resolve_user_params(params: &UserLogPathParams, app: &App) -> Result<(String, String)> {
     let channel_id = match params.channel_id_type {
         ChannelIdType::Name => app.get_user_id_by_name(&params.channel).await?,
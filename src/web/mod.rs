@@ -35,7 +35,18 @@ use tower_http::{
 };
 use tracing::{debug, info};
 
-const CAPABILITIES: &[&str] = &["arbitrary-range-query", "search", "stats", "namehistory"];
+const CAPABILITIES: &[&str] = &[
+    "arbitrary-range-query",
+    "search",
+    "channel-search",
+    "stats",
+    "namehistory",
+    "gaps",
+    "moderation",
+    "events",
+    "activity",
+    "leaderboard",
+];
 
 pub async fn run(app: App, mut shutdown_rx: ShutdownRx, bot_tx: Sender<BotMessage>) {
     aide::generate::on_error(|error| {
@@ -65,6 +76,24 @@ pub async fn run(app: App, mut shutdown_rx: ShutdownRx, bot_tx: Sender<BotMessag
                 op.tag("Admin").description("Leave the specified channels")
             }),
         )
+        .api_route(
+            "/optout/{user_id}",
+            get_with(admin::get_user_deletion_status, |mut op| {
+                admin::admin_auth_doc(&mut op);
+                op.tag("Admin")
+                    .description("Get the status of log deletion for an opted out user")
+            })
+            .post_with(admin::retry_user_deletion, |mut op| {
+                admin::admin_auth_doc(&mut op);
+                op.tag("Admin")
+                    .description("Start the log deletion for an opted out user again")
+            })
+            .delete_with(admin::remove_optout, |mut op| {
+                admin::admin_auth_doc(&mut op);
+                op.tag("Admin")
+                    .description("Remove an opt out, so the user's messages are logged again")
+            }),
+        )
         .route_layer(middleware::from_fn_with_state(app.clone(), admin_auth))
         .layer(Extension(bot_tx));
 
@@ -89,24 +118,96 @@ pub async fn run(app: App, mut shutdown_rx: ShutdownRx, bot_tx: Sender<BotMessag
                 op.description("Get user name history by provided user id")
             }),
         )
+        .api_route(
+            "/message/{id}",
+            get_with(handlers::get_message, |op| {
+                op.description("Get a single message by its id, optionally with surrounding messages from the same channel")
+            }),
+        )
+        .api_route(
+            "/user/{user}/channels",
+            get_with(handlers::get_user_channels, |op| {
+                op.description("List the channels a user has messages in")
+            }),
+        )
+        .api_route(
+            "/userid/{user}/channels",
+            get_with(handlers::get_user_id_channels, |op| {
+                op.description("List the channels a user has messages in, by user id")
+            }),
+        )
+        .api_route(
+            "/user/{user}/logs",
+            get_with(handlers::get_user_logs_all_channels, |op| {
+                op.description("Get user logs from all logged channels. Defaults to the last 30 days if no range is specified")
+            }),
+        )
+        .api_route(
+            "/userid/{user}/logs",
+            get_with(handlers::get_user_id_logs_all_channels, |op| {
+                op.description("Get user logs from all logged channels by user id. Defaults to the last 30 days if no range is specified")
+            }),
+        )
         .api_route(
             "/{channel_id_type}/{channel}/{user_id_type}/{user}/search",
             get_with(handlers::search_user_logs, |op| {
                 op.description("Search user logs using the provided query")
             }),
         )
+        .api_route(
+            "/{channel_id_type}/{channel}/search",
+            get_with(handlers::search_channel_logs, |op| {
+                op.description("Search the logs of all users in a channel using the provided query. The total amount of matches is returned in the X-Total-Count header")
+            }),
+        )
         .api_route(
             "/{channel_id_type}/{channel}/{user_id_type}/{user}/stats",
             get_with(handlers::get_user_stats, |op| {
                 op.description("Get user stats")
             }),
         )
+        .api_route(
+            "/{channel_id_type}/{channel}/stats/leaderboard",
+            get_with(handlers::get_channel_leaderboard, |op| {
+                op.description("Get the top chatters of a channel, ranked by message count or active days. The total amount of ranked users is returned in the X-Total-Count header")
+            }),
+        )
+        .api_route(
+            "/{channel_id_type}/{channel}/stats/activity",
+            get_with(handlers::get_channel_activity, |op| {
+                op.description("Get channel activity over time, grouped into minute, hour, day or week buckets. Defaults to the last 7 days if no range is specified")
+            }),
+        )
         .api_route(
             "/{channel_id_type}/{channel}/stats",
             get_with(handlers::get_channel_stats, |op| {
                 op.description("Get channel stats")
             }),
         )
+        .api_route(
+            "/{channel_id_type}/{channel}/{user_id_type}/{user}/moderation",
+            get_with(handlers::get_user_moderation, |op| {
+                op.description("List bans, timeouts and message deletions of a user in a channel, newest first")
+            }),
+        )
+        .api_route(
+            "/{channel_id_type}/{channel}/moderation",
+            get_with(handlers::get_channel_moderation, |op| {
+                op.description("List bans, timeouts and message deletions in a channel, newest first. Defaults to the last 30 days if no range is specified")
+            }),
+        )
+        .api_route(
+            "/{channel_id_type}/{channel}/events",
+            get_with(handlers::get_channel_events, |op| {
+                op.description("List subscriptions, gifts, raids, announcements and other user notice events in a channel with aggregated counts. Defaults to the last 30 days if no range is specified")
+            }),
+        )
+        .api_route(
+            "/{channel_id_type}/{channel}/gaps",
+            get_with(handlers::get_channel_gaps, |op| {
+                op.description("Get periods of time in which the channel was not being logged")
+            }),
+        )
         .api_route(
             "/{channel_id_type}/{channel}/random",
             get_with(handlers::random_channel_line, |op| {
@@ -144,6 +245,7 @@ pub async fn run(app: App, mut shutdown_rx: ShutdownRx, bot_tx: Sender<BotMessag
             }),
         )
         .api_route("/optout", post(handlers::optout))
+        .api_route("/optin", post(handlers::optin))
         .api_route("/capabilities", get(capabilities))
         .route("/docs", Scalar::new("/openapi.json").axum_route())
         .route("/openapi.json", get(serve_openapi))
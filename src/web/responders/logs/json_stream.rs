@@ -1,7 +1,10 @@
 use crate::{
     db::schema::StructuredMessage,
     logs::{
-        schema::message::{BasicMessage, FullMessage, ResponseMessage},
+        schema::{
+            message::{BasicMessage, FullMessage, ResponseMessage},
+            LogGap,
+        },
         stream::LogsStream,
     },
     Result,
@@ -18,6 +21,7 @@ use tracing::error;
 
 const HEADER: &str = r#"{"messages":["#;
 const FOOTER: &str = r#"]}"#;
+const GAPS_KEY: &str = r#"],"gaps":"#;
 /// Rough estimation of how big a single message is in JSON format
 const JSON_MESSAGE_SIZE: usize = 1024;
 const CHUNK_SIZE: usize = 3000;
@@ -32,16 +36,29 @@ pub struct JsonLogsStream {
     is_start: bool,
     is_end: bool,
     response_type: JsonResponseType,
+    gaps: Vec<LogGap>,
 }
 
 impl JsonLogsStream {
-    pub fn new(stream: LogsStream, response_type: JsonResponseType) -> Self {
+    pub fn new(stream: LogsStream, response_type: JsonResponseType, gaps: Vec<LogGap>) -> Self {
         let inner = stream.try_chunks(CHUNK_SIZE);
         Self {
             inner,
             is_start: true,
             is_end: false,
             response_type,
+            gaps,
+        }
+    }
+
+    fn footer(&self) -> Vec<u8> {
+        if self.gaps.is_empty() {
+            FOOTER.as_bytes().to_vec()
+        } else {
+            let mut buf = GAPS_KEY.as_bytes().to_vec();
+            serde_json::to_writer(&mut buf, &self.gaps).unwrap();
+            buf.push(b'}');
+            buf
         }
     }
 
@@ -118,11 +135,13 @@ impl Stream for JsonLogsStream {
             },
             Poll::Ready(None) => {
                 self.is_end = true;
-                // No lines were retrieved
+                // Every message was filtered out, which still has to be a valid response
                 if self.is_start {
-                    Poll::Ready(None)
+                    let mut buf = HEADER.as_bytes().to_vec();
+                    buf.extend(self.footer());
+                    Poll::Ready(Some(Ok(buf)))
                 } else {
-                    Poll::Ready(Some(Ok(FOOTER.as_bytes().to_vec())))
+                    Poll::Ready(Some(Ok(self.footer())))
                 }
             }
             Poll::Pending => Poll::Pending,
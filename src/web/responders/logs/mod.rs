@@ -7,7 +7,10 @@ pub use json_stream::JsonResponseType;
 use self::{
     json_stream::JsonLogsStream, ndjson_stream::NdJsonLogsStream, text_stream::TextLogsStream,
 };
-use crate::logs::{schema::message::FullMessage, stream::LogsStream};
+use crate::logs::{
+    schema::{message::FullMessage, LogGap},
+    stream::LogsStream,
+};
 use aide::OperationOutput;
 use axum::{
     body::Body,
@@ -24,6 +27,23 @@ use schemars::JsonSchema;
 pub struct LogsResponse {
     pub stream: LogsStream,
     pub response_type: LogsResponseType,
+    /// Logging gaps in the requested range, only included in JSON responses
+    pub gaps: Vec<LogGap>,
+}
+
+impl LogsResponse {
+    pub fn new(stream: LogsStream, response_type: LogsResponseType) -> Self {
+        Self {
+            stream,
+            response_type,
+            gaps: Vec::new(),
+        }
+    }
+
+    pub fn with_gaps(mut self, gaps: Vec<LogGap>) -> Self {
+        self.gaps = gaps;
+        self
+    }
 }
 
 pub enum LogsResponseType {
@@ -38,6 +58,9 @@ pub enum LogsResponseType {
 pub struct JsonLogsResponse<'a> {
     #[allow(dead_code)]
     pub messages: Vec<FullMessage<'a>>,
+    /// Periods of time in the requested range in which messages were not logged. Omitted if there are none
+    #[allow(dead_code)]
+    pub gaps: Option<Vec<LogGap>>,
 }
 
 impl IntoResponse for LogsResponse {
@@ -68,7 +91,7 @@ impl IntoResponse for LogsResponse {
                     .into_response()
             }
             LogsResponseType::Json(response_type) => {
-                let stream = JsonLogsStream::new(self.stream, response_type);
+                let stream = JsonLogsStream::new(self.stream, response_type, self.gaps);
                 (
                     set_content_type(&APPLICATION_JSON),
                     Body::from_stream(stream),
@@ -126,3 +149,84 @@ impl OperationOutput for LogsResponse {
         vec![(Some(200), res)]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{JsonResponseType, LogsResponse, LogsResponseType};
+    use crate::{
+        db::schema::{StructuredMessage, UnstructuredMessage},
+        logs::{
+            schema::{Deletions, LogGap},
+            stream::LogsStream,
+        },
+    };
+    use axum::{body::to_bytes, response::IntoResponse};
+    use chrono::{TimeZone, Utc};
+    use futures::executor::block_on;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    const MESSAGE_ID: &str = "0a4b7b50-052e-473e-99ee-441f05ce52a7";
+
+    fn message() -> StructuredMessage<'static> {
+        let raw = format!("@id={MESSAGE_ID};room-id=1;user-id=2;tmi-sent-ts=1686947117960 :user!user@user.tmi.twitch.tv PRIVMSG #channel :hello");
+        let unstructured = UnstructuredMessage {
+            channel_id: "1",
+            user_id: "2",
+            timestamp: 1686947117960,
+            raw: &raw,
+        };
+        StructuredMessage::from_unstructured(&unstructured)
+            .unwrap()
+            .into_owned()
+    }
+
+    fn body(logs: LogsResponse) -> String {
+        let body = logs.into_response().into_body();
+        let bytes = block_on(to_bytes(body, usize::MAX)).unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn all_messages_hidden() {
+        let deletions = Deletions {
+            message_ids: [Uuid::parse_str(MESSAGE_ID).unwrap()].into(),
+            ..Default::default()
+        };
+        let stream = LogsStream::new_provided(vec![message()])
+            .unwrap()
+            .with_deletions(deletions, true);
+
+        let logs = LogsResponse::new(stream, LogsResponseType::Json(JsonResponseType::Basic));
+
+        assert_eq!(r#"{"messages":[]}"#, body(logs));
+    }
+
+    #[test]
+    fn gaps_without_messages() {
+        let gap = LogGap {
+            from: Utc.timestamp_millis_opt(1686947117960).unwrap(),
+            to: None,
+            uncertain_start: false,
+        };
+        let logs = LogsResponse::new(
+            LogsStream::empty(),
+            LogsResponseType::Json(JsonResponseType::Full),
+        )
+        .with_gaps(vec![gap]);
+
+        let body: serde_json::Value = serde_json::from_str(&body(logs)).unwrap();
+        assert_eq!(
+            json!({
+                "messages": [],
+                "gaps": [{
+                    "from": "2023-06-16T20:25:17.960Z",
+                    "to": null,
+                    "uncertain_start": false,
+                }],
+            }),
+            body
+        );
+    }
+}
@@ -41,12 +41,15 @@ impl Stream for TextLogsStream {
                         let text = msg.user_friendly_text();
                         let channel = &msg.channel_login;
                         let username = &msg.user_login;
+                        let deleted = if msg.deleted { " (deleted)" } else { "" };
 
                         if !username.is_empty() {
-                            let _ =
-                                write!(output, "[{timestamp}] #{channel} {username}: {text}\r\n");
+                            let _ = write!(
+                                output,
+                                "[{timestamp}] #{channel} {username}: {text}{deleted}\r\n"
+                            );
                         } else {
-                            let _ = write!(output, "[{timestamp}] #{channel} {text}\r\n");
+                            let _ = write!(output, "[{timestamp}] #{channel} {text}{deleted}\r\n");
                         }
                     }
 
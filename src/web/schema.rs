@@ -1,4 +1,8 @@
 use super::responders::logs::{JsonResponseType, LogsResponseType};
+use crate::{
+    db::{schema::MessageFlags, search::SearchQuery},
+    logs::schema::event::{ChannelEvent, ChannelEventCounts},
+};
 use chrono::{DateTime, Utc};
 use schemars::JsonSchema;
 use serde::{Deserialize, Deserializer, Serialize};
@@ -77,6 +81,13 @@ pub struct LogsParams {
     pub reverse: bool,
     #[serde(default, deserialize_with = "deserialize_bool_param")]
     pub ndjson: bool,
+    /// Exclude messages from ignored users, such as bots
+    #[serde(default, deserialize_with = "deserialize_bool_param")]
+    pub exclude_bots: bool,
+    /// Leave out messages which were removed by moderators instead of marking them as deleted.
+    /// They are left out after `limit` and `offset` are applied, so pages can have fewer messages than `limit`
+    #[serde(default, deserialize_with = "deserialize_bool_param")]
+    pub hide_deleted: bool,
     pub limit: Option<u64>,
     pub offset: Option<u64>,
 }
@@ -106,9 +117,19 @@ where
 
 #[derive(Deserialize, Debug, JsonSchema)]
 pub struct SearchParams {
+    /// Words, `"phrases"` and `/regexes/` combined with `AND`, `OR`, `NOT` and parentheses.
+    /// Can be filtered with `from:user`, `type:usernotice`, `badge:moderator`, `flag:first-msg`,
+    /// `before:2024-01-31` and `after:2024-01-01`.
+    /// Regexes use RE2 syntax and match anywhere in the message unless anchored with `^` and `$`
     pub q: String,
 }
 
+impl SearchParams {
+    pub fn query(&self) -> Result<SearchQuery, String> {
+        self.q.parse()
+    }
+}
+
 #[derive(Serialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct AvailableLogs {
@@ -180,6 +201,208 @@ pub struct UserLogsStats {
     pub message_count: u64,
 }
 
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct LeaderboardParams {
+    /// Defaults to 10
+    pub limit: Option<u64>,
+    pub offset: Option<u64>,
+    #[serde(default)]
+    pub rank_by: LeaderboardRanking,
+    /// Only count messages of this type, such as `usernotice`. Defaults to `privmsg`
+    #[serde(rename = "type")]
+    pub message_type: Option<String>,
+    /// Only count messages which were sent with this badge
+    pub badge: Option<LeaderboardBadge>,
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum LeaderboardRanking {
+    #[default]
+    Messages,
+    /// Amount of distinct days on which the user sent a message
+    ActiveDays,
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LeaderboardBadge {
+    Subscriber,
+    Vip,
+    Moderator,
+}
+
+impl LeaderboardBadge {
+    pub fn flag(&self) -> MessageFlags {
+        match self {
+            LeaderboardBadge::Subscriber => MessageFlags::SUBSCRIBER,
+            LeaderboardBadge::Vip => MessageFlags::VIP,
+            LeaderboardBadge::Moderator => MessageFlags::MOD,
+        }
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct Leaderboard {
+    pub chatters: Vec<LeaderboardEntry>,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct LeaderboardEntry {
+    pub rank: u64,
+    pub user_id: String,
+    pub user_login: Option<String>,
+    pub message_count: u64,
+    pub active_days: u64,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct ChannelActivityParams {
+    #[serde(default)]
+    pub bucket: StatsBucket,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsBucket {
+    Minute,
+    #[default]
+    Hour,
+    Day,
+    /// Weeks start on Monday
+    Week,
+}
+
+impl StatsBucket {
+    pub fn seconds(&self) -> u64 {
+        match self {
+            StatsBucket::Minute => 60,
+            StatsBucket::Hour => 60 * 60,
+            StatsBucket::Day => 24 * 60 * 60,
+            StatsBucket::Week => 7 * 24 * 60 * 60,
+        }
+    }
+
+    /// Unix timestamp of the start of the bucket containing the timestamp in milliseconds
+    pub fn start(&self, timestamp: u64) -> u32 {
+        // The unix epoch was on a Thursday, so weeks are offset to start on Monday
+        let offset = match self {
+            StatsBucket::Week => 4 * 24 * 60 * 60,
+            _ => 0,
+        };
+        let seconds = (timestamp / 1000).saturating_sub(offset);
+        (seconds - seconds % self.seconds() + offset) as u32
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct ChannelActivity {
+    pub bucket: StatsBucket,
+    /// Periods without any activity between the first and last active bucket are included with zero counts
+    pub buckets: Vec<ActivityBucket>,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ActivityBucket {
+    /// Start of the bucket
+    pub timestamp: DateTime<Utc>,
+    pub message_count: u64,
+    pub unique_chatters: u64,
+    /// Users who sent their first message in the channel
+    pub first_time_chatters: u64,
+    /// Subs, resubs and gifted subs
+    pub sub_events: u64,
+    /// Bans, timeouts and message deletions
+    pub moderation_actions: u64,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct ModerationActionsList {
+    pub actions: Vec<ModerationAction>,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ModerationAction {
+    pub timestamp: DateTime<Utc>,
+    pub action: ModerationActionType,
+    /// Not known for message deletions
+    pub user_id: Option<String>,
+    pub user_login: String,
+    /// Timeout length in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u64>,
+    /// Id of the deleted message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_message_id: Option<String>,
+    /// Text of the deleted message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ModerationActionType {
+    Ban,
+    Timeout,
+    Deletion,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct ChannelEventsParams {
+    /// Comma-separated event types to include, such as `resub,subGift,raid`. Includes all types if not specified
+    #[serde(rename = "type")]
+    pub event_type: Option<String>,
+}
+
+impl ChannelEventsParams {
+    pub fn includes(&self, event_type: &str) -> bool {
+        self.event_type
+            .as_deref()
+            .is_none_or(|types| types.split(',').any(|value| value.trim() == event_type))
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct ChannelEventsList {
+    pub events: Vec<ChannelEvent>,
+    pub counts: ChannelEventCounts,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct MessagePath {
+    pub id: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct MessageContextParams {
+    /// How many messages of the same channel to include before and after the message
+    pub context: Option<u64>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct UserPath {
+    pub user: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct UserChannelsList {
+    pub channels: Vec<UserChannel>,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UserChannel {
+    pub channel_id: String,
+    pub channel_login: Option<String>,
+    pub message_count: u64,
+    pub first_timestamp: DateTime<Utc>,
+    pub last_timestamp: DateTime<Utc>,
+}
+
 #[derive(Deserialize, JsonSchema)]
 pub struct UserNameHistoryParam {
     pub user_id: String,
@@ -191,3 +414,41 @@ pub struct PreviousName {
     pub last_timestamp: DateTime<Utc>,
     pub first_timestamp: DateTime<Utc>,
 }
+
+#[derive(Deserialize, JsonSchema)]
+pub struct UserIdPath {
+    pub user_id: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UserDeletionStatus {
+    pub user_id: String,
+    pub opted_out: bool,
+    /// Whether all deletion mutations have finished.
+    /// `false` when no mutations were found, e.g. if the deletion failed to start
+    pub is_done: bool,
+    pub mutations: Vec<DeletionMutation>,
+    /// When the user opted out or back in, oldest first
+    pub history: Vec<OptOutChange>,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct OptOutChange {
+    pub timestamp: DateTime<Utc>,
+    /// Whether the user opted out or opted back in
+    pub opted_out: bool,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DeletionMutation {
+    pub table: String,
+    pub mutation_id: String,
+    pub created_at: DateTime<Utc>,
+    pub is_done: bool,
+    /// How many data parts still have to be processed
+    pub parts_to_do: u64,
+    pub latest_fail_reason: Option<String>,
+}